@@ -0,0 +1,6 @@
+pub use self::config::{
+    archive, export, get_env, graphite, influx_schema, meters, mqtt, postgres, spool, sqlite,
+    webhook,
+};
+#[allow(clippy::module_inception)]
+pub mod config;
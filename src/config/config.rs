@@ -0,0 +1,269 @@
+use dsmrlib::export::{Format, Rotation};
+use dsmrlib::{
+    Archive, Export, Graphite, InfluxSchema, Input, Mqtt, Postgres, Spool, Sqlite, Webhook,
+};
+use std::collections::HashSet;
+use std::env;
+use std::fmt::Display;
+use std::fs;
+use std::str::FromStr;
+use std::time::Duration;
+
+/// A meter to read from, `label` is added as a `meter` tag to its points.
+pub struct Meter {
+    pub label: Option<String>,
+    pub input: Input,
+}
+
+/// Reads and parses an optional environment variable.
+pub fn get_env<T>(key: &str) -> Result<Option<T>, String>
+where
+    T: FromStr,
+    T::Err: Display,
+{
+    match env::var(key) {
+        Ok(value) => value
+            .parse()
+            .map(Some)
+            .map_err(|e| format!("Invalid {} {}: {}", key, value, e)),
+        Err(_) => Ok(None),
+    }
+}
+
+/// MQTT broker to publish readings to.
+pub fn mqtt() -> Result<Option<Mqtt>, String> {
+    let url = match get_env("DSMR_MQTT_URL")? {
+        Some(url) => url,
+        None => return Ok(None),
+    };
+    let mut mqtt = Mqtt::new(url);
+    if let Some(client_id) = get_env("DSMR_MQTT_CLIENT_ID")? {
+        mqtt.client_id = client_id;
+    }
+    if let Some(prefix) = get_env("DSMR_MQTT_TOPIC")? {
+        mqtt.prefix = prefix;
+    }
+    mqtt.qos = get_env("DSMR_MQTT_QOS")?.unwrap_or(0);
+    mqtt.retain = get_env("DSMR_MQTT_RETAIN")?.unwrap_or(false);
+    mqtt.json = get_env("DSMR_MQTT_JSON")?.unwrap_or(false);
+    mqtt.raw = get_env("DSMR_MQTT_RAW")?.unwrap_or(false);
+    if get_env("DSMR_MQTT_DISCOVERY")?.unwrap_or(false) {
+        mqtt.discovery = Some(
+            get_env("DSMR_MQTT_DISCOVERY_PREFIX")?.unwrap_or_else(|| "homeassistant".to_string()),
+        );
+    }
+    mqtt.ca_file = get_env("DSMR_MQTT_CA_FILE")?;
+    mqtt.client_auth = match (
+        get_env("DSMR_MQTT_CLIENT_CERT")?,
+        get_env("DSMR_MQTT_CLIENT_KEY")?,
+    ) {
+        (Some(cert), Some(key)) => Some((cert, key)),
+        (None, None) => None,
+        _ => return Err("DSMR_MQTT_CLIENT_CERT and DSMR_MQTT_CLIENT_KEY go together".to_string()),
+    };
+    Ok(Some(mqtt))
+}
+
+/// CSV or Parquet files of readings in `DSMR_EXPORT_DIR`.
+pub fn export() -> Result<Option<Export>, String> {
+    match env::var("DSMR_EXPORT_DIR") {
+        Ok(directory) => Ok(Some(Export {
+            directory: directory.into(),
+            format: get_env("DSMR_EXPORT_FORMAT")?.unwrap_or(Format::Csv),
+            rotation: get_env("DSMR_EXPORT_ROTATION")?.unwrap_or(Rotation::Monthly),
+        })),
+        Err(_) => Ok(None),
+    }
+}
+
+/// PostgreSQL output from `DSMR_POSTGRES`, a connection string.
+pub fn postgres() -> Result<Option<Postgres>, String> {
+    let config: String = match get_env("DSMR_POSTGRES")? {
+        Some(config) => config,
+        None => return Ok(None),
+    };
+    let mut postgres = Postgres::new(&config);
+    postgres.timescale = get_env("DSMR_POSTGRES_TIMESCALE")?.unwrap_or(false);
+    if let Some(batch_size) = get_env("DSMR_POSTGRES_BATCH_SIZE")? {
+        postgres.batch_size = batch_size;
+    }
+    if let Some(interval) = get_env("DSMR_POSTGRES_FLUSH_INTERVAL")? {
+        postgres.flush_interval = Duration::from_secs(interval);
+    }
+    Ok(Some(postgres))
+}
+
+/// Layout of InfluxDB points, from the JSON file `INFLUX_DB_SCHEMA_FILE`,
+/// with the `name=value` pairs of `INFLUX_DB_TAGS` added to every point.
+pub fn influx_schema() -> Result<InfluxSchema, String> {
+    let mut schema = match get_env::<String>("INFLUX_DB_SCHEMA_FILE")? {
+        Some(path) => {
+            let schema =
+                fs::read_to_string(&path).map_err(|e| format!("Unable to read {}: {}", path, e))?;
+            serde_json::from_str(&schema).map_err(|e| format!("Invalid {}: {}", path, e))?
+        }
+        None => InfluxSchema::default(),
+    };
+    if let Some(tags) = get_env::<String>("INFLUX_DB_TAGS")? {
+        for tag in tags.split(',').filter(|tag| !tag.trim().is_empty()) {
+            match tag.split_once('=') {
+                Some((name, value)) => {
+                    schema
+                        .tags
+                        .insert(name.trim().to_string(), value.trim().to_string());
+                }
+                None => return Err(format!("Invalid tag in INFLUX_DB_TAGS: {}", tag)),
+            }
+        }
+    }
+    schema.validate()?;
+    Ok(schema)
+}
+
+/// Graphite Carbon receiver from `DSMR_GRAPHITE_ADDRESS`, on port 2003 unless
+/// one is given.
+pub fn graphite() -> Result<Option<Graphite>, String> {
+    let address = match get_env::<String>("DSMR_GRAPHITE_ADDRESS")? {
+        Some(address) if address.contains(':') => address,
+        Some(address) => format!("{}:2003", address),
+        None => return Ok(None),
+    };
+    let mut graphite = Graphite::new(&address);
+    if let Some(template) = get_env("DSMR_GRAPHITE_TEMPLATE")? {
+        graphite.template = template;
+        graphite.validate()?;
+    }
+    if let Some(batch_size) = get_env("DSMR_GRAPHITE_BATCH_SIZE")? {
+        graphite.batch_size = batch_size;
+    }
+    if let Some(interval) = get_env("DSMR_GRAPHITE_FLUSH_INTERVAL")? {
+        graphite.flush_interval = Duration::from_secs(interval);
+    }
+    Ok(Some(graphite))
+}
+
+/// HTTP webhook from `DSMR_WEBHOOK_URL`, with headers as `Name: value`
+/// pairs separated by `;` and the template inline or from a file.
+pub fn webhook() -> Result<Option<Webhook>, String> {
+    let url = match get_env("DSMR_WEBHOOK_URL")? {
+        Some(url) => url,
+        None => return Ok(None),
+    };
+    let mut webhook = Webhook::new(url);
+    if let Some(method) = get_env::<String>("DSMR_WEBHOOK_METHOD")? {
+        webhook.method = method
+            .to_uppercase()
+            .parse()
+            .map_err(|_| format!("Invalid DSMR_WEBHOOK_METHOD: {}", method))?;
+    }
+    if let Some(headers) = get_env::<String>("DSMR_WEBHOOK_HEADERS")? {
+        webhook.headers = headers
+            .split(';')
+            .filter(|header| !header.trim().is_empty())
+            .map(|header| match header.split_once(':') {
+                Some((name, value)) => Ok((name.trim().to_string(), value.trim().to_string())),
+                None => Err(format!(
+                    "Invalid header in DSMR_WEBHOOK_HEADERS: {}",
+                    header
+                )),
+            })
+            .collect::<Result<_, _>>()?;
+    }
+    webhook.token = get_env("DSMR_WEBHOOK_TOKEN")?;
+    webhook.template = match get_env::<String>("DSMR_WEBHOOK_TEMPLATE_FILE")? {
+        Some(path) => {
+            Some(fs::read_to_string(&path).map_err(|e| format!("Unable to read {}: {}", path, e))?)
+        }
+        None => get_env("DSMR_WEBHOOK_TEMPLATE")?,
+    };
+    if let Some(batch_size) = get_env("DSMR_WEBHOOK_BATCH_SIZE")? {
+        webhook.batch_size = batch_size;
+    }
+    if let Some(interval) = get_env("DSMR_WEBHOOK_FLUSH_INTERVAL")? {
+        webhook.flush_interval = Duration::from_secs(interval);
+    }
+    Ok(Some(webhook))
+}
+
+/// Spool for points that could not be written, its maximum size is set in MiB.
+pub fn spool() -> Result<Option<Spool>, String> {
+    match env::var("DSMR_SPOOL_DIR") {
+        Ok(directory) => {
+            let max_size: u64 = get_env("DSMR_SPOOL_MAX_SIZE")?.unwrap_or(100);
+            Ok(Some(Spool::new(directory.into(), max_size * 1024 * 1024)))
+        }
+        Err(_) => Ok(None),
+    }
+}
+
+/// SQLite history from `DSMR_SQLITE_PATH`, with the retention in days in
+/// `DSMR_SQLITE_RETENTION_DAYS`.
+pub fn sqlite() -> Result<Option<Sqlite>, String> {
+    match env::var("DSMR_SQLITE_PATH") {
+        Ok(path) => {
+            let days: Option<u64> = get_env("DSMR_SQLITE_RETENTION_DAYS")?;
+            Ok(Some(Sqlite {
+                path: path.into(),
+                retention: days.map(|days| Duration::from_secs(days * 24 * 3600)),
+            }))
+        }
+        Err(_) => Ok(None),
+    }
+}
+
+/// Meters from `DSMR_METERS`, a comma separated list of `label=input`, or
+/// the single unlabeled meter in `DSMR_INPUT`.
+pub fn meters() -> Result<Vec<Meter>, String> {
+    match env::var("DSMR_METERS") {
+        Ok(meters) => parse_meters(&meters),
+        Err(_) => Ok(vec![Meter {
+            label: None,
+            input: get_env("DSMR_INPUT")?
+                .unwrap_or_else(|| Input::Serial("/dev/ttyUSB0".to_string())),
+        }]),
+    }
+}
+
+fn parse_meters(meters: &str) -> Result<Vec<Meter>, String> {
+    let mut labels = HashSet::new();
+    meters
+        .split(',')
+        .map(|meter| match meter.trim().split_once('=') {
+            Some((label, input)) if labels.insert(label.to_string()) => Ok(Meter {
+                label: Some(label.to_string()),
+                input: input.parse()?,
+            }),
+            Some((label, _)) => Err(format!("Meter {} is configured twice", label)),
+            None => Err(format!("Meter {} should look like label=input", meter)),
+        })
+        .collect()
+}
+
+pub fn archive() -> Result<Option<Archive>, String> {
+    match env::var("DSMR_ARCHIVE_DIR") {
+        Ok(directory) => Ok(Some(Archive {
+            directory: directory.into(),
+            compression: get_env("DSMR_ARCHIVE_COMPRESSION")?
+                .unwrap_or(dsmrlib::archive::Compression::Zstd),
+        })),
+        Err(_) => Ok(None),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_meters() {
+        let meters =
+            parse_meters("flat1=/dev/ttyUSB0, flat2=http://10.0.0.5/api/v1/data?interval=5")
+                .unwrap();
+        assert_eq!(meters.len(), 2);
+        assert_eq!(meters[0].label, Some("flat1".to_string()));
+        assert_eq!(meters[0].input, Input::Serial("/dev/ttyUSB0".to_string()));
+        assert_eq!(meters[1].label, Some("flat2".to_string()));
+        assert!(parse_meters("flat1=/dev/ttyUSB0,flat1=/dev/ttyUSB1").is_err());
+        assert!(parse_meters("/dev/ttyUSB0").is_err());
+    }
+}
@@ -1,4 +0,0 @@
-pub use self::dsmrlib::DsmrClient;
-pub mod dsmrlib;
-pub use env_logger::init;
-extern crate env_logger;
@@ -0,0 +1,155 @@
+use crate::{Reading, Timestamp, UsageData};
+use chrono::TimeZone;
+use std::time::Duration;
+
+#[derive(Debug, Default)]
+struct Series {
+    count: u32,
+    sum: f64,
+    min: f64,
+    max: f64,
+}
+
+impl Series {
+    fn add(&mut self, value: f64) {
+        if self.count == 0 {
+            self.min = value;
+            self.max = value;
+        }
+        self.count += 1;
+        self.sum += value;
+        self.min = self.min.min(value);
+        self.max = self.max.max(value);
+    }
+}
+
+/// Downsamples telegrams into one record per interval of meter time.
+///
+/// Instantaneous readings (power, voltage and current) become the mean over
+/// the interval with its min and max, cumulative registers keep the last
+/// value. Records are stamped with the start of their interval.
+pub(crate) struct Aggregator {
+    interval: i64,
+    bucket: Option<i64>,
+    series: [Series; 4],
+    last: Option<UsageData>,
+}
+
+fn instantaneous(data: &mut UsageData) -> [&mut Reading; 4] {
+    [
+        &mut data.power_receiving,
+        &mut data.power_returning,
+        &mut data.voltage,
+        &mut data.current,
+    ]
+}
+
+impl Aggregator {
+    pub(crate) fn new(interval: Duration) -> Self {
+        Aggregator {
+            interval: (interval.as_secs() as i64).max(1),
+            bucket: None,
+            series: Default::default(),
+            last: None,
+        }
+    }
+
+    /// Adds a telegram, returns the record of the previous interval once the
+    /// telegram falls in a new one.
+    pub(crate) fn push(&mut self, mut data: UsageData) -> Option<UsageData> {
+        let bucket = match &data.electricity_timestamp {
+            Reading::Timestamp(t) => t.timestamp.timestamp().div_euclid(self.interval),
+            Reading::Measurement(_) => return Some(data),
+        };
+        let record = match self.bucket {
+            Some(current) if current != bucket => self.flush(),
+            _ => None,
+        };
+        self.bucket = Some(bucket);
+        for (series, reading) in self.series.iter_mut().zip(instantaneous(&mut data)) {
+            if let Reading::Measurement(m) = reading {
+                series.add(m.value);
+            }
+        }
+        self.last = Some(data);
+        record
+    }
+
+    /// Returns the record of the current, possibly partial, interval.
+    pub(crate) fn flush(&mut self) -> Option<UsageData> {
+        let mut data = self.last.take()?;
+        for (series, reading) in self.series.iter_mut().zip(instantaneous(&mut data)) {
+            if let Reading::Measurement(m) = reading {
+                if series.count > 0 {
+                    m.value = series.sum / series.count as f64;
+                    m.min = Some(series.min);
+                    m.max = Some(series.max);
+                }
+            }
+            *series = Series::default();
+        }
+        if let (Reading::Timestamp(t), Some(bucket)) = (&data.electricity_timestamp, self.bucket) {
+            if let Some(start) = t
+                .timestamp
+                .offset()
+                .timestamp_opt(bucket * self.interval, 0)
+                .single()
+            {
+                data.electricity_timestamp = Reading::Timestamp(Timestamp { timestamp: start });
+            }
+        }
+        Some(data)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{deserialise_p1_message, sample_lines};
+
+    fn telegram(time: &str, power: &str, reading: &str) -> UsageData {
+        let mut lines = sample_lines();
+        lines[0] = format!("0-0:1.0.0({}W)", time);
+        lines[1] = format!("1-0:1.8.1({}*kWh)", reading);
+        lines[5] = format!("1-0:1.7.0({}*kW)", power);
+        deserialise_p1_message(&lines).unwrap()
+    }
+
+    fn value(reading: &Reading) -> (f64, Option<f64>, Option<f64>) {
+        match reading {
+            Reading::Measurement(m) => (m.value, m.min, m.max),
+            Reading::Timestamp(_) => panic!("not a measurement"),
+        }
+    }
+
+    #[test]
+    fn test_aggregate() {
+        let mut aggregator = Aggregator::new(Duration::from_secs(10));
+        assert!(aggregator
+            .push(telegram("201221010830", "00.100", "002134.100"))
+            .is_none());
+        assert!(aggregator
+            .push(telegram("201221010835", "00.300", "002134.200"))
+            .is_none());
+        let record = aggregator
+            .push(telegram("201221010841", "01.000", "002134.300"))
+            .unwrap();
+        let (mean, min, max) = value(&record.power_receiving);
+        assert!((mean - 0.2).abs() < 1e-9);
+        assert_eq!((min, max), (Some(0.1), Some(0.3)));
+        assert_eq!(
+            value(&record.electricity_reading_low_tariff),
+            (2134.2, None, None)
+        );
+        match record.electricity_timestamp {
+            Reading::Timestamp(t) => {
+                assert_eq!(t.timestamp.to_string(), "2020-12-21 01:08:30 +01:00")
+            }
+            Reading::Measurement(_) => panic!("not a timestamp"),
+        }
+
+        let record = aggregator.flush().unwrap();
+        assert_eq!(value(&record.power_receiving), (1.0, Some(1.0), Some(1.0)));
+        assert!(aggregator.flush().is_none());
+    }
+}
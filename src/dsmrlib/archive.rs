@@ -0,0 +1,263 @@
+use crate::queue::{self, DropPolicy, Sender};
+use crate::RawTelegram;
+use chrono::{Local, NaiveDate, SecondsFormat};
+use flate2::write::GzEncoder;
+use log::{error, info};
+use std::fs::{self, File, OpenOptions};
+use std::io::prelude::*;
+use std::io::{self, BufReader, BufWriter};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use tokio::task::{self, JoinHandle};
+
+/// Compression applied to the daily archive files.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Compression {
+    None,
+    Gzip,
+    Zstd,
+}
+
+impl Compression {
+    fn extension(&self) -> &'static str {
+        match self {
+            Compression::None => "txt",
+            Compression::Gzip => "txt.gz",
+            Compression::Zstd => "txt.zst",
+        }
+    }
+}
+
+impl FromStr for Compression {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(Compression::None),
+            "gzip" => Ok(Compression::Gzip),
+            "zstd" => Ok(Compression::Zstd),
+            _ => Err(format!("Unsupported archive compression: {}", s)),
+        }
+    }
+}
+
+/// Archive of every raw telegram, split into one file per day.
+///
+/// Each telegram is written byte for byte, including its CRC line, preceded by
+/// a `#` line with the time it was received. Archive files can be replayed
+/// with a `file://` input.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Archive {
+    pub directory: PathBuf,
+    pub compression: Compression,
+}
+
+impl Archive {
+    /// Starts writing the archive, telegrams are handed to the writer through
+    /// the returned queue so a slow disk never holds up reading the meter.
+    /// The task ends and finishes the file once the sender is dropped.
+    pub fn spawn(
+        &self,
+        queue_size: usize,
+        drop_policy: DropPolicy,
+    ) -> (Sender<RawTelegram>, JoinHandle<()>) {
+        let (sender, mut receiver) = queue::channel(queue_size, drop_policy);
+        let mut writer = ArchiveWriter::new(self.clone());
+        let handle = tokio::spawn(async move {
+            while let Some(telegram) = receiver.recv().await {
+                writer = match task::spawn_blocking(move || {
+                    if let Err(e) = writer.write(&telegram) {
+                        error!("Unable to archive telegram: {}", e);
+                    }
+                    writer
+                })
+                .await
+                {
+                    Ok(writer) => writer,
+                    Err(e) => return error!("Archive writer failed: {}", e),
+                };
+            }
+        });
+        (sender, handle)
+    }
+}
+
+struct ArchiveWriter {
+    archive: Archive,
+    date: Option<NaiveDate>,
+    file: Option<Box<dyn Write + Send>>,
+}
+
+impl ArchiveWriter {
+    fn new(archive: Archive) -> Self {
+        ArchiveWriter {
+            archive,
+            date: None,
+            file: None,
+        }
+    }
+
+    fn write(&mut self, telegram: &RawTelegram) -> io::Result<()> {
+        let today = telegram.received.with_timezone(&Local).date_naive();
+        if self.date != Some(today) {
+            self.rotate(today)?;
+        }
+        match self.file.as_mut() {
+            Some(file) => {
+                write!(
+                    file,
+                    "#{}\r\n",
+                    telegram
+                        .received
+                        .to_rfc3339_opts(SecondsFormat::Millis, true)
+                )?;
+                for line in telegram.lines.iter() {
+                    write!(file, "{}\r\n", line)?;
+                }
+                file.flush()
+            }
+            None => Ok(()),
+        }
+    }
+
+    /// Finishes the current file and starts a new one for `date`. Compressed
+    /// files are never appended to, a restart on the same day gets a new
+    /// numbered file so an unfinished stream cannot corrupt the next one.
+    fn rotate(&mut self, date: NaiveDate) -> io::Result<()> {
+        self.file = None;
+        fs::create_dir_all(&self.archive.directory)?;
+        let extension = self.archive.compression.extension();
+        let mut path = self
+            .archive
+            .directory
+            .join(format!("telegrams-{}.{}", date, extension));
+        let mut count = 1;
+        while self.archive.compression != Compression::None && path.exists() {
+            path = self
+                .archive
+                .directory
+                .join(format!("telegrams-{}-{}.{}", date, count, extension));
+            count += 1;
+        }
+        let file = BufWriter::new(OpenOptions::new().create(true).append(true).open(&path)?);
+        info!("Archiving telegrams to {}", path.display());
+        self.file = Some(match self.archive.compression {
+            Compression::None => Box::new(file),
+            Compression::Gzip => Box::new(GzEncoder::new(file, flate2::Compression::default())),
+            Compression::Zstd => Box::new(zstd::Encoder::new(file, 0)?.auto_finish()),
+        });
+        self.date = Some(date);
+        Ok(())
+    }
+}
+
+/// Opens a file written by the archive, decompressing it based on its extension.
+pub(crate) fn open_archive_file(path: &Path) -> io::Result<Box<dyn BufRead + Send>> {
+    let file = File::open(path)?;
+    Ok(match path.extension().and_then(|e| e.to_str()) {
+        Some("gz") => Box::new(BufReader::new(flate2::read::MultiGzDecoder::new(file))),
+        Some("zst") => Box::new(BufReader::new(zstd::Decoder::new(file)?)),
+        _ => Box::new(BufReader::new(file)),
+    })
+}
+
+/// Date of an archive file and the number a restart on the same day added
+/// to its name, `None` for files not named by the archive.
+fn archive_order(path: &Path) -> Option<(NaiveDate, u32)> {
+    let name = path.file_name()?.to_str()?.strip_prefix("telegrams-")?;
+    let date = NaiveDate::parse_from_str(name.get(..10)?, "%Y-%m-%d").ok()?;
+    let suffix = name[10..].split('.').next()?;
+    let count = match suffix.strip_prefix('-') {
+        Some(count) => count.parse().ok()?,
+        None if suffix.is_empty() => 0,
+        None => return None,
+    };
+    Some((date, count))
+}
+
+/// Sorts archive files in the order they were written, by date and restart
+/// number, so `-10` comes after `-9`. Other files go first, sorted by name.
+pub fn sort_archive_files(paths: &mut [PathBuf]) {
+    paths.sort_by_cached_key(|path| (archive_order(path), path.clone()));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Input;
+    use chrono::Utc;
+    use std::env;
+
+    const TELEGRAM: [&str; 4] = [
+        "/ISK5\\2M550E-1012",
+        "",
+        "0-0:1.0.0(201221010833W)",
+        "!5C6B",
+    ];
+
+    #[tokio::test]
+    async fn test_archive_roundtrip() {
+        for compression in [Compression::None, Compression::Gzip, Compression::Zstd].iter() {
+            let directory =
+                env::temp_dir().join(format!("energise_test_archive_{:?}", compression));
+            let _ = fs::remove_dir_all(&directory);
+            let mut writer = ArchiveWriter::new(Archive {
+                directory: directory.clone(),
+                compression: *compression,
+            });
+            let telegram = RawTelegram {
+                received: Utc::now(),
+                lines: TELEGRAM.iter().map(|l| l.to_string()).collect(),
+            };
+            writer.write(&telegram).unwrap();
+            writer.write(&telegram).unwrap();
+            drop(writer);
+
+            let file = fs::read_dir(&directory).unwrap().next().unwrap().unwrap();
+            let input = Input::Replay {
+                path: file.path(),
+                speed: 0.0,
+            };
+            let mut lines = input.open().await.unwrap();
+            let mut replayed = Vec::new();
+            while let Some(line) = lines.next_line().await {
+                replayed.push(line);
+            }
+            assert_eq!(replayed.len(), 10);
+            assert!(replayed[0].starts_with('#'));
+            assert_eq!(&replayed[1..5], &TELEGRAM[..]);
+            assert_eq!(&replayed[6..10], &TELEGRAM[..]);
+            fs::remove_dir_all(directory).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_sort_archive_files() {
+        let mut paths: Vec<PathBuf> = [
+            "archive/telegrams-2024-05-02.txt.zst",
+            "archive/telegrams-2024-05-01-10.txt.zst",
+            "archive/telegrams-2024-05-01-2.txt.zst",
+            "archive/telegrams-2024-05-01.txt.zst",
+            "capture.txt",
+            "archive/telegrams-2024-05-01-1.txt.zst",
+        ]
+        .iter()
+        .map(PathBuf::from)
+        .collect();
+        sort_archive_files(&mut paths);
+        assert_eq!(
+            paths,
+            [
+                "capture.txt",
+                "archive/telegrams-2024-05-01.txt.zst",
+                "archive/telegrams-2024-05-01-1.txt.zst",
+                "archive/telegrams-2024-05-01-2.txt.zst",
+                "archive/telegrams-2024-05-01-10.txt.zst",
+                "archive/telegrams-2024-05-02.txt.zst",
+            ]
+            .iter()
+            .map(PathBuf::from)
+            .collect::<Vec<_>>()
+        );
+    }
+}
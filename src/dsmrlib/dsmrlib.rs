@@ -2,148 +2,506 @@ use chrono::DateTime;
 use chrono::FixedOffset;
 use chrono::NaiveDateTime;
 use chrono::TimeZone;
-use influx_db_client::{points, Point, Points, Precision, Value};
-use log::{debug, error, info};
+use chrono::Utc;
+use influx_db_client::Points;
+use log::{error, info, warn};
 use serde::{Deserialize, Serialize};
-use serialport::SerialPortSettings;
 use std::collections::HashMap;
-use std::io::prelude::*;
-use std::io::BufReader;
+use std::future::Future;
 use std::io::ErrorKind;
-use std::sync::mpsc::{self, Receiver, Sender};
-use std::thread;
+use std::str::FromStr;
+use std::sync::Arc;
 use std::time::Duration;
+use tokio::sync::oneshot;
+use tokio::time::{sleep, timeout_at, Instant};
 extern crate env_logger;
 
-const BAUD_RATE: u32 = 115_200;
-const TIMEOUT: u64 = 1000;
+mod aggregate;
+pub mod archive;
+pub mod export;
+pub mod graphite;
+pub mod history;
+mod homeassistant;
+mod homewizard;
+pub mod http;
+pub mod influx;
+pub mod input;
+pub mod live;
+pub mod metrics;
+#[cfg(test)]
+mod mock;
+pub mod mqtt;
+pub mod ndjson;
+pub mod postgres;
+pub mod queue;
+mod replay;
+pub mod sink;
+pub mod spool;
+pub mod sqlite;
+pub mod watchdog;
+pub mod webhook;
+use self::aggregate::Aggregator;
+pub use self::archive::Archive;
+pub use self::export::{export_archive, Export};
+pub use self::graphite::Graphite;
+pub use self::history::History;
+pub use self::http::HttpServer;
+pub use self::influx::{InfluxDb, InfluxDbV2, InfluxSchema, InfluxSink, WriteError};
+pub use self::input::{Input, Lines};
+pub use self::live::Live;
+pub use self::metrics::Metrics;
+pub use self::mqtt::Mqtt;
+pub use self::ndjson::Ndjson;
+pub use self::postgres::Postgres;
+pub use self::queue::DropPolicy;
+pub use self::sink::{Output, Retry, Sink, SinkError};
+pub use self::spool::Spool;
+pub use self::sqlite::Sqlite;
+pub use self::watchdog::Watchdog;
+pub use self::webhook::Webhook;
+
+const RESTART_DELAY: u64 = 1;
+const MAX_RESTART_DELAY: u64 = 60;
 
 #[derive(Debug)]
 pub struct DsmrClient {
-    pub serial_device: String,
-    pub influx_db: influx_db_client::Client,
+    /// Label passed to the sinks with every telegram, to tell meters apart.
+    pub meter: Option<String>,
+    pub input: Input,
+    pub archive: Option<Archive>,
+    /// Number of telegrams buffered between reading the meter and handing them
+    /// to the sinks.
+    pub queue_size: usize,
+    /// What happens to new telegrams while the buffer is full.
+    pub drop_policy: DropPolicy,
+    pub watchdog: Watchdog,
+    /// Downsample telegrams into one record per interval before writing them.
+    pub aggregate: Option<Duration>,
+    /// Where telegrams are written to, each sink gets every telegram.
+    pub sinks: Vec<Output>,
+}
+
+/// Clock used for the timestamp of written points.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TimestampSource {
+    /// The meter's own capture time, `0-0:1.0.0` for electricity and the
+    /// capture time of each M-Bus value, falling back to the host receive
+    /// time when a telegram carries none.
+    Meter,
+    /// The time the host received the telegram.
+    Host,
+}
+
+impl FromStr for TimestampSource {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "meter" => Ok(TimestampSource::Meter),
+            "host" => Ok(TimestampSource::Host),
+            _ => Err(format!("Unknown timestamp source: {}", s)),
+        }
+    }
+}
+
+impl TimestampSource {
+    /// Time of the readings stamped with `reading`, one of the timestamps of
+    /// `data`.
+    pub(crate) fn time(self, data: &UsageData, reading: &Reading) -> Option<DateTime<Utc>> {
+        match (self, reading) {
+            (TimestampSource::Meter, Reading::Timestamp(t)) => {
+                Some(t.timestamp.with_timezone(&Utc))
+            }
+            _ => data.received,
+        }
+    }
 }
 
-#[derive(Debug, Deserialize, Serialize, PartialEq)]
-struct UsageData {
+/// A telegram as read from the meter, from its `/` header up to and
+/// including the `!` CRC line.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RawTelegram {
+    pub received: DateTime<Utc>,
+    pub lines: Vec<String>,
+}
+
+impl RawTelegram {
+    /// Checks the CRC16 of the telegram, from `/` up to and including `!`.
+    /// DSMR versions before 4 send no CRC, their telegrams give `None`.
+    pub fn crc_valid(&self) -> Option<bool> {
+        let (last, lines) = self.lines.split_last()?;
+        let expected = u16::from_str_radix(last.strip_prefix('!')?.trim(), 16).ok()?;
+        let mut crc: u16 = 0;
+        let bytes = lines
+            .iter()
+            .flat_map(|l| l.bytes().chain(*b"\r\n"))
+            .chain(Some(b'!'));
+        for byte in bytes {
+            crc ^= byte as u16;
+            for _ in 0..8 {
+                crc = if crc & 1 == 1 {
+                    (crc >> 1) ^ 0xa001
+                } else {
+                    crc >> 1
+                };
+            }
+        }
+        Some(crc == expected)
+    }
+}
+
+/// A parsed telegram.
+///
+/// Deserialized from OBIS references, serialized with the field names as
+/// keys, measurements as `{"value": 0.229, "unit": "kW"}` and timestamps as
+/// RFC 3339 strings.
+#[derive(Debug, Clone, Deserialize, Serialize, PartialEq)]
+pub struct UsageData {
     #[serde(rename(deserialize = "0-0:1.0.0"))]
-    electricity_timestamp: Reading,
+    pub electricity_timestamp: Reading,
     #[serde(rename(deserialize = "1-0:1.7.0"))]
-    power_receiving: Reading,
+    pub power_receiving: Reading,
     #[serde(rename(deserialize = "1-0:2.7.0"))]
-    power_returning: Reading,
+    pub power_returning: Reading,
     #[serde(rename(deserialize = "1-0:2.8.1"))]
-    electricity_returned_reading_low_tariff: Reading,
+    pub electricity_returned_reading_low_tariff: Reading,
     #[serde(rename(deserialize = "1-0:2.8.2"))]
-    electricity_returned_reading_normal_tariff: Reading,
+    pub electricity_returned_reading_normal_tariff: Reading,
     #[serde(rename(deserialize = "1-0:1.8.1"))]
-    electricity_reading_low_tariff: Reading,
+    pub electricity_reading_low_tariff: Reading,
     #[serde(rename(deserialize = "1-0:1.8.2"))]
-    electricity_reading_normal_tariff: Reading,
+    pub electricity_reading_normal_tariff: Reading,
     #[serde(rename(deserialize = "0-1:24.2.1"))]
-    gas_reading: Reading,
-    gas_timestamp: Reading,
+    pub gas_reading: Reading,
+    pub gas_timestamp: Reading,
     #[serde(rename(deserialize = "1-0:32.7.0"))]
-    voltage: Reading,
+    pub voltage: Reading,
     #[serde(rename(deserialize = "1-0:31.7.0"))]
-    current: Reading,
+    pub current: Reading,
+    /// When the host received the telegram.
+    #[serde(skip_deserializing, skip_serializing_if = "Option::is_none")]
+    pub received: Option<DateTime<Utc>>,
+    /// The telegram as received.
+    #[serde(skip)]
+    pub lines: Vec<String>,
 }
 
-#[derive(Debug, Deserialize, Serialize, PartialEq)]
-enum Reading {
+impl UsageData {
+    /// Every reading with its energy type and field name.
+    pub fn readings(&self) -> [(&'static str, &'static str, &Reading); 9] {
+        [
+            (
+                "electricity",
+                "electricity_reading_low_tariff",
+                &self.electricity_reading_low_tariff,
+            ),
+            (
+                "electricity",
+                "electricity_reading_normal_tariff",
+                &self.electricity_reading_normal_tariff,
+            ),
+            (
+                "electricity",
+                "electricity_returned_reading_low_tariff",
+                &self.electricity_returned_reading_low_tariff,
+            ),
+            (
+                "electricity",
+                "electricity_returned_reading_normal_tariff",
+                &self.electricity_returned_reading_normal_tariff,
+            ),
+            ("electricity", "power_receiving", &self.power_receiving),
+            ("electricity", "power_returning", &self.power_returning),
+            ("electricity", "voltage", &self.voltage),
+            ("electricity", "current", &self.current),
+            ("gas", "gas_reading", &self.gas_reading),
+        ]
+    }
+
+    /// Equipment identifier of the electricity meter from `0-0:96.1.1`,
+    /// decoded from hex when it is printable ASCII.
+    pub fn equipment_id(&self) -> Option<String> {
+        let id = self
+            .lines
+            .iter()
+            .find_map(|l| l.strip_prefix("0-0:96.1.1("))?
+            .trim_end_matches(')');
+        if id.is_empty() {
+            return None;
+        }
+        let decoded: Option<String> = (0..id.len())
+            .step_by(2)
+            .map(|i| {
+                id.get(i..i + 2)
+                    .and_then(|b| u8::from_str_radix(b, 16).ok())
+                    .filter(|b| b.is_ascii_graphic())
+                    .map(char::from)
+            })
+            .collect();
+        Some(decoded.unwrap_or_else(|| id.to_string()))
+    }
+
+    /// Instantaneous values per phase, of the phases the meter reports, read
+    /// from the telegram as received. For an aggregated record these are the
+    /// values of the last telegram in the interval, not the mean.
+    pub fn phases(&self) -> Vec<Phase> {
+        let value = |obis: &str| -> Option<f64> {
+            let value = self
+                .lines
+                .iter()
+                .find_map(|l| l.strip_prefix(obis)?.strip_prefix('('))?;
+            value.trim_end_matches(')').split('*').next()?.parse().ok()
+        };
+        let measurement = |reading: &Reading| match reading {
+            Reading::Measurement(m) => Some(m.value),
+            Reading::Timestamp(_) => None,
+        };
+        let mut phases: Vec<Phase> = [
+            ("1-0:32.7.0", "1-0:31.7.0", "1-0:21.7.0", "1-0:22.7.0"),
+            ("1-0:52.7.0", "1-0:51.7.0", "1-0:41.7.0", "1-0:42.7.0"),
+            ("1-0:72.7.0", "1-0:71.7.0", "1-0:61.7.0", "1-0:62.7.0"),
+        ]
+        .iter()
+        .zip(1..)
+        .map(|((voltage, current, receiving, returning), phase)| Phase {
+            phase,
+            voltage: value(voltage),
+            current: value(current),
+            power_receiving: value(receiving),
+            power_returning: value(returning),
+        })
+        .collect();
+        // Without the telegram only L1 is known, from its fields
+        if self.lines.is_empty() {
+            phases[0].voltage = measurement(&self.voltage);
+            phases[0].current = measurement(&self.current);
+        }
+        phases.retain(|phase| phase.voltage.is_some() || phase.current.is_some());
+        phases
+    }
+}
+
+/// Instantaneous values of one phase.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Phase {
+    pub phase: u8,
+    pub voltage: Option<f64>,
+    pub current: Option<f64>,
+    pub power_receiving: Option<f64>,
+    pub power_returning: Option<f64>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize, PartialEq)]
+#[serde(untagged)]
+pub enum Reading {
     Measurement(Measurement),
     Timestamp(Timestamp),
 }
 
-#[derive(Debug, Deserialize, Serialize, PartialEq)]
-struct Measurement {
-    value: f64,
-    unit: String,
+#[derive(Debug, Clone, Deserialize, Serialize, PartialEq)]
+pub struct Measurement {
+    pub value: f64,
+    pub unit: String,
+    /// Smallest and largest value when aggregated over an interval.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub min: Option<f64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max: Option<f64>,
 }
 
-#[derive(Debug, Deserialize, Serialize, PartialEq)]
-struct Timestamp {
-    timestamp: chrono::DateTime<FixedOffset>,
+#[derive(Debug, Clone, Deserialize, Serialize, PartialEq)]
+#[serde(transparent)]
+pub struct Timestamp {
+    pub timestamp: chrono::DateTime<FixedOffset>,
 }
 
 impl DsmrClient {
-    pub async fn send_to_influxdb(self) {
-        let (sender, receiver): (Sender<UsageData>, Receiver<UsageData>) = mpsc::channel();
-        let mut settings: SerialPortSettings = Default::default();
-        settings.timeout = Duration::from_millis(TIMEOUT);
-        settings.baud_rate = BAUD_RATE;
-        let port = serialport::open_with_settings(&self.serial_device, &settings);
-
-        match port {
-            Ok(p) => {
-                info!(
-                    "Receiving data on {} at {} baud:",
-                    &self.serial_device, &settings.baud_rate
-                );
+    /// Reads the meter and writes every telegram to all sinks, until a
+    /// replayed input ends and the sinks have caught up.
+    pub async fn run(self) {
+        self.run_until(std::future::pending()).await
+    }
 
-                let data_iter = BufReader::new(p).lines().map(|lines| {
-                    debug!("lines mapping: {:?}", lines);
-                    lines.unwrap()
-                });
-                let data_thread = thread::spawn(|| get_meter_data(Box::new(data_iter), sender));
-                loop {
-                    let data = receiver.recv();
-                    match data {
-                        Ok(d) => {
-                            self.influx_db
-                                .write_points(
-                                    usage_to_points(&d).unwrap(),
-                                    Some(Precision::Seconds),
-                                    None,
-                                )
-                                .await
-                                .ok();
-                        }
-                        Err(_) => {
-                            return;
-                        }
+    /// Like `run`, but stops reading once `shutdown` completes. The telegrams
+    /// already read, and an interval partly aggregated, are still written and
+    /// every sink is closed.
+    pub async fn run_until(self, shutdown: impl Future<Output = ()>) {
+        let (sender, mut receiver) = queue::channel(self.queue_size, self.drop_policy);
+        let (archive, archiver) = self
+            .archive
+            .as_ref()
+            .map(|archive| archive.spawn(self.queue_size, self.drop_policy))
+            .unzip();
+        let (stop, stopped) = oneshot::channel::<()>();
+        tokio::spawn(get_meter_data(
+            self.input.clone(),
+            self.watchdog.clone(),
+            self.aggregate.map(Aggregator::new),
+            sender,
+            archive,
+            async {
+                stopped.await.ok();
+            },
+        ));
+        let mut stop = Some(stop);
+
+        let meter = self.meter;
+        let (senders, handles): (Vec<_>, Vec<_>) = self
+            .sinks
+            .into_iter()
+            .map(|output| output.spawn(meter.clone()))
+            .unzip();
+        tokio::pin!(shutdown);
+        loop {
+            let d = tokio::select! {
+                d = receiver.recv() => match d {
+                    Some(d) => d,
+                    None => break,
+                },
+                // Ends the reader, dropping its sender so the queue drains
+                _ = &mut shutdown, if stop.is_some() => {
+                    info!("Stopping {}", self.input);
+                    if let Some(stop) = stop.take() {
+                        stop.send(()).ok();
                     }
-                    data_thread.thread().unpark();
+                    continue;
                 }
+            };
+            let d = Arc::new(d);
+            for sender in &senders {
+                sender.send(d.clone()).await.ok();
+            }
+        }
+        drop(senders);
+        for handle in handles {
+            if let Err(e) = handle.await {
+                error!("Sink failed: {}", e);
+            }
+        }
+        if let Some(archiver) = archiver {
+            if let Err(e) = archiver.await {
+                error!("Archive failed: {}", e);
             }
-            Err(e) => panic!("Unable to connect to Serial Port: {}, retrying", e),
         }
     }
 }
 
-fn get_meter_data(
-    mut lines_iter: Box<dyn Iterator<Item = String>>,
-    sender: Sender<UsageData>,
-) -> Result<(), ErrorKind> {
+/// Reads telegrams until a replayed input ends or `stop` completes. Live
+/// inputs are reopened when they fail or the watchdog sees no valid telegram
+/// for too long. Parsed telegrams are queued for the consumer and never
+/// waited on, unless the drop policy is `Block`.
+async fn get_meter_data(
+    input: Input,
+    watchdog: Watchdog,
+    mut aggregator: Option<Aggregator>,
+    sender: queue::Sender<UsageData>,
+    archive: Option<queue::Sender<RawTelegram>>,
+    stop: impl Future<Output = ()>,
+) {
     info!("Reading meter data");
+    tokio::select! {
+        _ = read_meter_data(&input, &watchdog, &mut aggregator, &sender, archive) => {}
+        _ = stop => info!("Stopped reading {}", input),
+    }
+    // The interval collected so far is not lost when the input ends
+    if let Some(record) = aggregator.as_mut().and_then(|a| a.flush()) {
+        sender.send(record).await.ok();
+    }
+}
+
+async fn read_meter_data(
+    input: &Input,
+    watchdog: &Watchdog,
+    aggregator: &mut Option<Aggregator>,
+    sender: &queue::Sender<UsageData>,
+    archive: Option<queue::Sender<RawTelegram>>,
+) {
+    let mut delay = RESTART_DELAY;
     loop {
-        let message: Vec<std::string::String> = lines_iter
-            .by_ref()
-            .skip_while(|l| !l.starts_with('/'))
-            .take_while(|l| !l.starts_with('!'))
-            .collect();
-        let result = deserialise_p1_message(message);
-        match result {
-            Ok(r) => sender.send(r).map_err(|_| ErrorKind::BrokenPipe)?,
+        let mut lines = match input.open().await {
+            Ok(lines) => lines,
             Err(e) => {
-                error!("Failure to deserialise p1 message: {}", e);
+                error!(
+                    "Unable to connect to {}: {}, retrying in {}s",
+                    input, e, delay
+                );
+                sleep(Duration::from_secs(delay)).await;
+                delay = (delay * 2).min(MAX_RESTART_DELAY);
                 continue;
             }
+        };
+        let mut last_valid = Instant::now();
+        loop {
+            let telegram = match watchdog.window(input) {
+                Some(window) => {
+                    match timeout_at(last_valid + window, lines.next_telegram()).await {
+                        Ok(telegram) => telegram,
+                        Err(_) => {
+                            watchdog.stalled(input, window);
+                            break;
+                        }
+                    }
+                }
+                None => lines.next_telegram().await,
+            };
+            let telegram = match telegram {
+                Some(telegram) => telegram,
+                None if matches!(input, Input::Replay { .. }) => {
+                    return info!("No more meter data");
+                }
+                None => {
+                    error!("Lost input {}, restarting", input);
+                    break;
+                }
+            };
+            if telegram.crc_valid() == Some(false) {
+                warn!("CRC mismatch in telegram from {}", input);
+                watchdog.crc_failed();
+            }
+            let result = deserialise_p1_message(&telegram.lines).map(|mut r| {
+                r.received = Some(telegram.received);
+                r.lines = telegram.lines.clone();
+                r
+            });
+            if result.is_ok() {
+                last_valid = Instant::now();
+                delay = RESTART_DELAY;
+                watchdog.alive(&telegram);
+            }
+            if let Some(archive) = &archive {
+                archive.send(telegram).await.ok();
+            }
+            let result = match aggregator.as_mut() {
+                Some(aggregator) => result.map(|r| aggregator.push(r)),
+                None => result.map(Some),
+            };
+            match result {
+                Ok(Some(r)) => {
+                    if sender.send(r).await.is_err() {
+                        return;
+                    }
+                }
+                Ok(None) => {}
+                Err(e) => {
+                    watchdog.parse_failed();
+                    error!("Failure to deserialise p1 message: {}", e)
+                }
+            }
         }
-        thread::park();
+        watchdog.restarted();
+        sleep(Duration::from_secs(delay)).await;
+        delay = (delay * 2).min(MAX_RESTART_DELAY);
     }
 }
 
-fn deserialise_p1_message(
-    message: Vec<std::string::String>,
-) -> Result<UsageData, serde_json::Error> {
+fn deserialise_p1_message(message: &[String]) -> Result<UsageData, serde_json::Error> {
     let mut hash: HashMap<String, Reading> = HashMap::new();
     for item in message.iter() {
         let a = item.replace(")", "");
         let x: Vec<&str> = a.split('(').collect();
         if x.len() > 1 {
             // Timestamps have a different format than the rest of P1 the records so we need to catch it and parse it first
-            if x[0].to_string() == "0-0:1.0.0" {
+            if x[0] == "0-0:1.0.0" {
                 let timestamp = parse_date(x[1], "%y%m%d%H%M%S");
                 match timestamp {
                     Ok(t) => {
@@ -156,7 +514,7 @@ fn deserialise_p1_message(
                 }
             }
             // Gas is an exception because it posts two values of timestamp and reading instead of just a reading
-            if x[0].to_string() == "0-1:24.2.1" {
+            if x[0] == "0-1:24.2.1" {
                 let timestamp = parse_date(x[1], "%y%m%d%H%M%S");
                 match timestamp {
                     Ok(t) => {
@@ -176,6 +534,8 @@ fn deserialise_p1_message(
                             Reading::Measurement(Measurement {
                                 value: g,
                                 unit: gas_volume[1].to_string(),
+                                min: None,
+                                max: None,
                             }),
                         );
                     }
@@ -192,6 +552,8 @@ fn deserialise_p1_message(
                                 Reading::Measurement(Measurement {
                                     value: r,
                                     unit: z[1].to_string(),
+                                    min: None,
+                                    max: None,
                                 }),
                             );
                         }
@@ -201,10 +563,7 @@ fn deserialise_p1_message(
             }
         }
     }
-    let deserialised: UsageData = serde_json::from_value(match serde_json::to_value(&hash) {
-        Ok(it) => it,
-        Err(err) => return Err(err),
-    })?;
+    let deserialised: UsageData = serde_json::from_value(serde_json::to_value(&hash)?)?;
     Ok(deserialised)
 }
 
@@ -214,10 +573,14 @@ fn parse_date(date: &str, fmt: &str) -> Result<DateTime<FixedOffset>, ErrorKind>
         FixedOffset::east_opt(secs).expect("FixedOffset::east out of bounds")
     };
     let cet: FixedOffset = FixedOffset::east_opt(3600).expect("FixedOffset::east out of bounds");
-    if let Ok(naive_date) = NaiveDateTime::parse_from_str(&date[0..date.len() - 1], fmt) {
-        let offset = match date.chars().last() {
-            Some('W') => cet,
-            Some('S') => cest,
+    let (local, season) = match date.char_indices().last() {
+        Some((i, season)) => (&date[..i], season),
+        None => return Err(ErrorKind::InvalidData),
+    };
+    if let Ok(naive_date) = NaiveDateTime::parse_from_str(local, fmt) {
+        let offset = match season {
+            'W' => cet,
+            'S' => cest,
             _ => return Err(ErrorKind::InvalidData),
         };
         let datetime = offset.from_local_datetime(&naive_date).single();
@@ -231,114 +594,95 @@ fn parse_date(date: &str, fmt: &str) -> Result<DateTime<FixedOffset>, ErrorKind>
     }
 }
 
-fn usage_to_points(data: &UsageData) -> Result<Points, ErrorKind> {
-    let electricity_reading_low_tariff = create_point(
-        "dsmr",
-        "electricity",
-        "low_tariff",
-        &data.electricity_reading_low_tariff,
-        &data.electricity_timestamp,
-    );
-    let electricity_reading_normal_tariff = create_point(
-        "dsmr",
-        "electricity",
-        "normal_tariff",
-        &data.electricity_reading_normal_tariff,
-        &data.electricity_timestamp,
-    );
-    let electricity_returned_reading_low_tariff = create_point(
-        "dsmr",
-        "electricity",
+/// Value of the `reading` tag of every field of `UsageData`.
+pub(crate) const READING_TAGS: [(&str, &str); 9] = [
+    ("electricity_reading_low_tariff", "low_tariff"),
+    ("electricity_reading_normal_tariff", "normal_tariff"),
+    (
+        "electricity_returned_reading_low_tariff",
         "returned_reading_low_tariff",
-        &data.electricity_returned_reading_low_tariff,
-        &data.electricity_timestamp,
-    );
-    let electricity_returned_reading_normal_tariff = create_point(
-        "dsmr",
-        "electricity",
+    ),
+    (
+        "electricity_returned_reading_normal_tariff",
         "returned_reading_normal_tariff",
-        &data.electricity_returned_reading_normal_tariff,
-        &data.electricity_timestamp,
-    );
-    let power_receiving = create_point(
-        "dsmr",
-        "electricity",
-        "receiving",
-        &data.power_receiving,
-        &data.electricity_timestamp,
-    );
-    let power_returning = create_point(
-        "dsmr",
-        "electricity",
-        "returning",
-        &data.power_returning,
-        &data.electricity_timestamp,
-    );
-    let gas_reading = create_point(
-        "dsmr",
-        "gas",
-        "receiving",
-        &data.gas_reading,
-        &data.gas_timestamp,
-    );
-    let voltage = create_point(
-        "dsmr",
-        "electricity",
-        "voltage",
-        &data.voltage,
-        &data.electricity_timestamp,
-    );
-    let current = create_point(
-        "dsmr",
-        "electricity",
-        "current",
-        &data.current,
-        &data.electricity_timestamp,
-    );
-    let points = points!(
-        electricity_reading_low_tariff,
-        electricity_reading_normal_tariff,
-        electricity_returned_reading_low_tariff,
-        electricity_returned_reading_normal_tariff,
-        power_receiving,
-        power_returning,
-        gas_reading,
-        voltage,
-        current
-    );
-    Ok(points)
+    ),
+    ("power_receiving", "receiving"),
+    ("power_returning", "returning"),
+    ("voltage", "voltage"),
+    ("current", "current"),
+    ("gas_reading", "receiving"),
+];
+
+pub(crate) fn usage_to_points(
+    data: &UsageData,
+    meter: Option<&str>,
+    timestamps: TimestampSource,
+    schema: &InfluxSchema,
+) -> Result<Points, ErrorKind> {
+    let mut points = Vec::new();
+    for (energy_type, field, reading) in data.readings().iter() {
+        let tag = READING_TAGS
+            .iter()
+            .find(|(name, _)| name == field)
+            .map_or(*field, |(_, tag)| tag);
+        let timestamp = if *energy_type == "gas" {
+            &data.gas_timestamp
+        } else {
+            &data.electricity_timestamp
+        };
+        let timestamp = timestamps.time(data, timestamp).map(|t| t.timestamp());
+        if let Some(point) = schema.point(field, energy_type, tag, reading, meter, timestamp) {
+            points.push(point);
+        }
+    }
+    Ok(Points::create_new(points))
 }
 
-fn create_point(
-    name: &str,
-    energy_type: &str,
-    reading: &str,
-    value: &Reading,
-    _timestamp: &Reading,
-) -> Point {
-    Point::new(name)
-        .add_tag("energy_type", Value::String(energy_type.to_string()))
-        .add_tag("reading", Value::String(reading.to_string()))
-        .add_field(
-            "value",
-            Value::Float(match value {
-                Reading::Measurement(value) => value.value,
-                _ => 0.0,
-            }),
-        )
-        .add_tag(
-            "unit",
-            Value::String(match value {
-                Reading::Measurement(value) => value.unit.to_string(),
-                _ => "Nothing".to_string(),
-            }),
-        )
+/// Lines of a single phase telegram, shared by the tests.
+#[cfg(test)]
+pub(crate) fn sample_lines() -> Vec<String> {
+    [
+        "0-0:1.0.0(201221010833W)",
+        "1-0:1.8.1(002134.177*kWh)",
+        "1-0:1.8.2(003448.211*kWh)",
+        "1-0:2.8.1(000000.000*kWh)",
+        "1-0:2.8.2(000000.000*kWh)",
+        "1-0:1.7.0(00.229*kW)",
+        "1-0:2.7.0(00.000*kW)",
+        "1-0:32.7.0(236.7*V)",
+        "1-0:31.7.0(001*A)",
+        "0-1:24.2.1(201221010511W)(03799.479*m3)",
+    ]
+    .iter()
+    .map(|line| line.to_string())
+    .collect()
+}
+
+/// `sample_lines` as the meter sends them, with the header and CRC line.
+#[cfg(test)]
+pub(crate) fn sample_telegram() -> Vec<String> {
+    let mut lines = sample_lines();
+    lines.splice(0..0, ["/ISK5\\2M550E-1012".to_string(), String::new()]);
+    lines.push("!5C6B".to_string());
+    lines
 }
 
+/// `sample_telegram` as text, the way it is read from a capture file.
 #[cfg(test)]
+pub(crate) fn sample_text() -> String {
+    sample_telegram().join("\r\n") + "\r\n"
+}
 
+/// The telegram of `sample_lines`, parsed.
+#[cfg(test)]
+pub(crate) fn sample_usage() -> UsageData {
+    deserialise_p1_message(&sample_lines()).unwrap()
+}
+
+#[cfg(test)]
 mod tests {
     use super::*;
+    use influx_db_client::Value;
     // use chrono::FixedOffset;
     // use chrono::TimeZone;
 
@@ -409,45 +753,61 @@ mod tests {
         // let message = Vec::new();
         // message.push("/ISK5\\2M550E-1012");
 
-        let result = deserialise_p1_message(message);
+        let result = deserialise_p1_message(&message);
 
         let expected_data = UsageData {
             electricity_timestamp: Reading::Timestamp(Timestamp {
-                timestamp: FixedOffset::east_opt(1 * 3600)
+                timestamp: FixedOffset::east_opt(3600)
                     .unwrap()
                     .with_ymd_and_hms(2020, 12, 21, 1, 8, 33)
                     .unwrap(),
             }),
+            received: None,
+            lines: Vec::new(),
             power_receiving: Reading::Measurement(Measurement {
                 value: 0.229,
                 unit: "kW".to_string(),
+                min: None,
+                max: None,
             }),
             power_returning: Reading::Measurement(Measurement {
                 value: 0.0,
                 unit: "kW".to_string(),
+                min: None,
+                max: None,
             }),
             electricity_returned_reading_low_tariff: Reading::Measurement(Measurement {
                 value: 0.0,
                 unit: "kWh".to_string(),
+                min: None,
+                max: None,
             }),
             electricity_returned_reading_normal_tariff: Reading::Measurement(Measurement {
                 value: 0.0,
                 unit: "kWh".to_string(),
+                min: None,
+                max: None,
             }),
             electricity_reading_low_tariff: Reading::Measurement(Measurement {
                 value: 2134.177,
                 unit: "kWh".to_string(),
+                min: None,
+                max: None,
             }),
             electricity_reading_normal_tariff: Reading::Measurement(Measurement {
                 value: 3448.211,
                 unit: "kWh".to_string(),
+                min: None,
+                max: None,
             }),
             gas_reading: Reading::Measurement(Measurement {
                 value: 3799.479,
                 unit: "m3".to_string(),
+                min: None,
+                max: None,
             }),
             gas_timestamp: Reading::Timestamp(Timestamp {
-                timestamp: FixedOffset::east_opt(1 * 3600)
+                timestamp: FixedOffset::east_opt(3600)
                     .unwrap()
                     .with_ymd_and_hms(2010, 12, 21, 1, 5, 11)
                     .unwrap(),
@@ -455,10 +815,14 @@ mod tests {
             voltage: Reading::Measurement(Measurement {
                 value: 236.7,
                 unit: "V".to_string(),
+                min: None,
+                max: None,
             }),
             current: Reading::Measurement(Measurement {
                 value: 1.0,
                 unit: "A".to_string(),
+                min: None,
+                max: None,
             }),
         };
         match result {
@@ -466,4 +830,140 @@ mod tests {
             Err(e) => error!("{}", e),
         };
     }
+
+    #[test]
+    fn test_point_timestamps() {
+        let mut data = sample_usage();
+        data.received = Some(Utc.with_ymd_and_hms(2020, 12, 21, 0, 8, 34).unwrap());
+        let timestamp = |points: &Points, energy_type: &str| {
+            points
+                .point
+                .iter()
+                .find(|p| p.tags["energy_type"] == Value::String(energy_type.to_string()))
+                .unwrap()
+                .timestamp
+        };
+
+        let points = usage_to_points(
+            &data,
+            None,
+            TimestampSource::Meter,
+            &InfluxSchema::default(),
+        )
+        .unwrap();
+        assert_eq!(timestamp(&points, "electricity"), Some(1608509313));
+        assert_eq!(timestamp(&points, "gas"), Some(1608509111));
+
+        let points =
+            usage_to_points(&data, None, TimestampSource::Host, &InfluxSchema::default()).unwrap();
+        assert_eq!(timestamp(&points, "electricity"), Some(1608509314));
+        assert_eq!(timestamp(&points, "gas"), Some(1608509314));
+    }
+
+    #[test]
+    fn test_phases() {
+        let mut lines = sample_lines();
+        let data = deserialise_p1_message(&lines).unwrap();
+        assert_eq!(
+            data.phases(),
+            vec![Phase {
+                phase: 1,
+                voltage: Some(236.7),
+                current: Some(1.0),
+                power_receiving: None,
+                power_returning: None,
+            }]
+        );
+
+        lines.extend(
+            [
+                "1-0:52.7.0(231.2*V)",
+                "1-0:51.7.0(004*A)",
+                "1-0:42.7.0(00.912*kW)",
+            ]
+            .iter()
+            .map(|l| l.to_string()),
+        );
+        let mut data = deserialise_p1_message(&lines).unwrap();
+        data.lines = lines;
+        let phases = data.phases();
+        assert_eq!(phases.len(), 2);
+        assert_eq!(
+            phases[1],
+            Phase {
+                phase: 2,
+                voltage: Some(231.2),
+                current: Some(4.0),
+                power_receiving: None,
+                power_returning: Some(0.912),
+            }
+        );
+
+        // All phases come from the same telegram, also when the L1 fields
+        // hold the mean of an interval
+        data.voltage = Reading::Measurement(Measurement {
+            value: 230.0,
+            unit: "V".to_string(),
+            min: Some(225.0),
+            max: Some(236.7),
+        });
+        assert_eq!(data.phases()[0].voltage, Some(236.7));
+    }
+
+    #[test]
+    fn test_crc() {
+        let mut telegram = RawTelegram {
+            received: Utc::now(),
+            lines: vec![
+                "/ISK5\\2M550E-1012".to_string(),
+                "".to_string(),
+                "1-3:0.2.8(50)".to_string(),
+                "0-0:1.0.0(201221010833W)".to_string(),
+                "1-0:1.8.1(002134.177*kWh)".to_string(),
+                "1-0:1.7.0(00.229*kW)".to_string(),
+                "!4497".to_string(),
+            ],
+        };
+        assert_eq!(telegram.crc_valid(), Some(true));
+        telegram.lines[5] = "1-0:1.7.0(00.228*kW)".to_string();
+        assert_eq!(telegram.crc_valid(), Some(false));
+        telegram.lines[6] = "!".to_string();
+        assert_eq!(telegram.crc_valid(), None);
+    }
+
+    #[tokio::test]
+    async fn test_get_meter_data() {
+        let path = std::env::temp_dir().join("energise_test_get_meter_data.txt");
+        let telegram = sample_text();
+        std::fs::write(&path, format!("garbage\r\n{}{}", telegram, telegram)).unwrap();
+
+        let input = Input::Replay {
+            path: path.clone(),
+            speed: 0.0,
+        };
+        let (sender, mut receiver) = queue::channel(1, DropPolicy::Block);
+        tokio::spawn(get_meter_data(
+            input,
+            Watchdog::default(),
+            None,
+            sender,
+            None,
+            std::future::pending(),
+        ));
+        let mut count = 0;
+        while let Some(data) = receiver.recv().await {
+            assert_eq!(
+                data.power_receiving,
+                Reading::Measurement(Measurement {
+                    value: 0.229,
+                    unit: "kW".to_string(),
+                    min: None,
+                    max: None,
+                })
+            );
+            count += 1;
+        }
+        assert_eq!(count, 2);
+        std::fs::remove_file(path).unwrap();
+    }
 }
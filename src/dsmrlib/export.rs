@@ -0,0 +1,535 @@
+use crate::archive::open_archive_file;
+use crate::{deserialise_p1_message, Reading, Sink, SinkError, UsageData};
+use arrow_array::{ArrayRef, Float64Array, RecordBatch, StringArray, TimestampMillisecondArray};
+use arrow_schema::{DataType, Field, Schema, TimeUnit};
+use async_trait::async_trait;
+use chrono::{DateTime, Local, SecondsFormat, Utc};
+use log::{error, info, warn};
+use parquet::arrow::ArrowWriter;
+use parquet::basic::{Compression, ZstdLevel};
+use parquet::file::properties::WriterProperties;
+use std::fs::{self, File, OpenOptions};
+use std::io::prelude::*;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc::{self, Sender};
+use tokio::task;
+
+const READ_AHEAD: usize = 16;
+/// Rows in a Parquet row group, bounding the rows held in memory.
+const ROW_GROUP_SIZE: usize = 10_000;
+/// Longest time rows are held before they are written as a row group.
+const FLUSH_INTERVAL: Duration = Duration::from_secs(15 * 60);
+
+/// File format of exported readings.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Format {
+    Csv,
+    Parquet,
+}
+
+impl Format {
+    fn extension(&self) -> &'static str {
+        match self {
+            Format::Csv => "csv",
+            Format::Parquet => "parquet",
+        }
+    }
+}
+
+impl FromStr for Format {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "csv" => Ok(Format::Csv),
+            "parquet" => Ok(Format::Parquet),
+            _ => Err(format!("Unsupported export format: {}", s)),
+        }
+    }
+}
+
+/// How often a new export file is started, in local time.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Rotation {
+    Daily,
+    Monthly,
+}
+
+impl Rotation {
+    fn period(&self, time: DateTime<Utc>) -> String {
+        let format = match self {
+            Rotation::Daily => "%Y-%m-%d",
+            Rotation::Monthly => "%Y-%m",
+        };
+        time.with_timezone(&Local).format(format).to_string()
+    }
+}
+
+impl FromStr for Rotation {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "daily" => Ok(Rotation::Daily),
+            "monthly" => Ok(Rotation::Monthly),
+            _ => Err(format!("Unsupported export rotation: {}", s)),
+        }
+    }
+}
+
+/// Parsed readings written to CSV or Parquet files, one row per telegram.
+///
+/// The columns are `meter`, `received`, `electricity_timestamp`, every
+/// reading of `UsageData::readings` in its unit and `gas_timestamp`. Rows go
+/// to a new file every day or month by meter time. CSV files are appended to,
+/// Parquet files can't be and are finished on rotation and once the input
+/// ends, so a restart in the same period starts a new numbered file. Until
+/// then a Parquet file is written next to it with a `.tmp` extension.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Export {
+    pub directory: PathBuf,
+    pub format: Format,
+    pub rotation: Rotation,
+}
+
+impl Export {
+    pub fn sink(&self) -> ExportSink {
+        ExportSink {
+            writer: Arc::new(Mutex::new(ExportWriter {
+                export: self.clone(),
+                period: None,
+                file: None,
+                oldest: None,
+            })),
+        }
+    }
+}
+
+/// A row of the export.
+struct Row {
+    meter: Option<String>,
+    received: Option<DateTime<Utc>>,
+    electricity_timestamp: Option<DateTime<Utc>>,
+    readings: Vec<(&'static str, Option<f64>)>,
+    gas_timestamp: Option<DateTime<Utc>>,
+}
+
+impl Row {
+    fn new(meter: Option<&str>, data: &UsageData) -> Self {
+        let time = |reading: &Reading| match reading {
+            Reading::Timestamp(t) => Some(t.timestamp.with_timezone(&Utc)),
+            Reading::Measurement(_) => None,
+        };
+        Row {
+            meter: meter.map(str::to_string),
+            received: data.received,
+            electricity_timestamp: time(&data.electricity_timestamp),
+            readings: data
+                .readings()
+                .iter()
+                .map(|(_, name, reading)| match reading {
+                    Reading::Measurement(m) => (*name, Some(m.value)),
+                    Reading::Timestamp(_) => (*name, None),
+                })
+                .collect(),
+            gas_timestamp: time(&data.gas_timestamp),
+        }
+    }
+
+    fn columns(&self) -> Vec<&'static str> {
+        let mut columns = vec!["meter", "received", "electricity_timestamp"];
+        columns.extend(self.readings.iter().map(|(name, _)| *name));
+        columns.push("gas_timestamp");
+        columns
+    }
+
+    fn to_csv(&self) -> Vec<String> {
+        let time = |t: Option<DateTime<Utc>>| {
+            t.map(|t| t.to_rfc3339_opts(SecondsFormat::Secs, true))
+                .unwrap_or_default()
+        };
+        let mut record = vec![
+            self.meter.clone().unwrap_or_default(),
+            time(self.received),
+            time(self.electricity_timestamp),
+        ];
+        record.extend(
+            self.readings
+                .iter()
+                .map(|(_, value)| value.map(|v| v.to_string()).unwrap_or_default()),
+        );
+        record.push(time(self.gas_timestamp));
+        record
+    }
+
+    fn to_record_batch(&self) -> Result<RecordBatch, SinkError> {
+        let time = |t: Option<DateTime<Utc>>| -> ArrayRef {
+            Arc::new(
+                TimestampMillisecondArray::from(vec![t.map(|t| t.timestamp_millis())])
+                    .with_timezone("UTC"),
+            )
+        };
+        let mut columns: Vec<ArrayRef> = vec![
+            Arc::new(StringArray::from(vec![self.meter.clone()])),
+            time(self.received),
+            time(self.electricity_timestamp),
+        ];
+        columns.extend(
+            self.readings
+                .iter()
+                .map(|(_, value)| Arc::new(Float64Array::from(vec![*value])) as ArrayRef),
+        );
+        columns.push(time(self.gas_timestamp));
+        Ok(RecordBatch::try_new(Arc::new(self.schema()), columns)?)
+    }
+
+    fn schema(&self) -> Schema {
+        let timestamp = DataType::Timestamp(TimeUnit::Millisecond, Some("UTC".into()));
+        let fields: Vec<Field> = self
+            .columns()
+            .into_iter()
+            .map(|name| match name {
+                "meter" => Field::new(name, DataType::Utf8, true),
+                "received" | "electricity_timestamp" | "gas_timestamp" => {
+                    Field::new(name, timestamp.clone(), true)
+                }
+                _ => Field::new(name, DataType::Float64, true),
+            })
+            .collect();
+        Schema::new(fields)
+    }
+}
+
+enum ExportFile {
+    Csv(csv::Writer<File>),
+    /// Written to a temporary file that is renamed to `path` once finished.
+    Parquet {
+        writer: ArrowWriter<File>,
+        path: PathBuf,
+    },
+}
+
+/// Path a Parquet file is written to until it is finished.
+fn temporary(path: &Path) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(".tmp");
+    PathBuf::from(name)
+}
+
+struct ExportWriter {
+    export: Export,
+    period: Option<String>,
+    file: Option<ExportFile>,
+    /// When the oldest row not yet in a Parquet row group was written.
+    oldest: Option<Instant>,
+}
+
+impl ExportWriter {
+    fn write(&mut self, row: &Row) -> Result<(), SinkError> {
+        let time = row
+            .electricity_timestamp
+            .or(row.received)
+            .unwrap_or_else(Utc::now);
+        let period = self.export.rotation.period(time);
+        if self.period.as_ref() != Some(&period) {
+            self.rotate(period, row)?;
+        }
+        match self.file.as_mut() {
+            Some(ExportFile::Csv(writer)) => {
+                writer.write_record(row.to_csv())?;
+                writer.flush()?;
+            }
+            Some(ExportFile::Parquet { writer, .. }) => {
+                writer.write(&row.to_record_batch()?)?;
+                // A full row group is written by the writer itself
+                if writer.in_progress_rows() == 0 {
+                    self.oldest = None;
+                } else {
+                    self.oldest.get_or_insert_with(Instant::now);
+                }
+            }
+            None => {}
+        }
+        Ok(())
+    }
+
+    /// Writes the buffered Parquet rows as a row group once they are old
+    /// enough.
+    fn flush(&mut self) -> Result<(), SinkError> {
+        if let (Some(ExportFile::Parquet { writer, .. }), Some(oldest)) =
+            (self.file.as_mut(), self.oldest)
+        {
+            if oldest.elapsed() >= FLUSH_INTERVAL {
+                writer.flush()?;
+                self.oldest = None;
+            }
+        }
+        Ok(())
+    }
+
+    /// Finishes the current file and starts the file of `period`.
+    fn rotate(&mut self, period: String, row: &Row) -> Result<(), SinkError> {
+        self.finish()?;
+        fs::create_dir_all(&self.export.directory)?;
+        let extension = self.export.format.extension();
+        let mut path = self
+            .export
+            .directory
+            .join(format!("readings-{}.{}", period, extension));
+        let mut count = 1;
+        while self.export.format == Format::Parquet && (path.exists() || temporary(&path).exists())
+        {
+            path = self
+                .export
+                .directory
+                .join(format!("readings-{}-{}.{}", period, count, extension));
+            count += 1;
+        }
+        info!("Exporting readings to {}", path.display());
+        self.file = Some(match self.export.format {
+            Format::Csv => {
+                let file = OpenOptions::new().create(true).append(true).open(&path)?;
+                let new = file.metadata()?.len() == 0;
+                let mut writer = csv::Writer::from_writer(file);
+                if new {
+                    writer.write_record(row.columns())?;
+                }
+                ExportFile::Csv(writer)
+            }
+            Format::Parquet => {
+                let file = File::create(temporary(&path))?;
+                let properties = WriterProperties::builder()
+                    .set_compression(Compression::ZSTD(ZstdLevel::default()))
+                    .set_max_row_group_size(ROW_GROUP_SIZE)
+                    .build();
+                let schema = Arc::new(row.schema());
+                let writer = ArrowWriter::try_new(file, schema, Some(properties))?;
+                ExportFile::Parquet { writer, path }
+            }
+        });
+        self.period = Some(period);
+        Ok(())
+    }
+
+    fn finish(&mut self) -> Result<(), SinkError> {
+        match self.file.take() {
+            Some(ExportFile::Csv(mut writer)) => writer.flush()?,
+            Some(ExportFile::Parquet { writer, path }) => {
+                writer.close()?;
+                fs::rename(temporary(&path), &path)?;
+            }
+            None => {}
+        }
+        self.period = None;
+        self.oldest = None;
+        Ok(())
+    }
+}
+
+pub struct ExportSink {
+    writer: Arc<Mutex<ExportWriter>>,
+}
+
+#[async_trait]
+impl Sink for ExportSink {
+    fn name(&self) -> String {
+        "export".to_string()
+    }
+
+    async fn write(&mut self, meter: Option<&str>, data: &UsageData) -> Result<(), SinkError> {
+        let writer = self.writer.clone();
+        let row = Row::new(meter, data);
+        task::spawn_blocking(move || writer.lock().unwrap().write(&row)).await?
+    }
+
+    async fn flush(&mut self) -> Result<(), SinkError> {
+        let writer = self.writer.clone();
+        task::spawn_blocking(move || writer.lock().unwrap().flush()).await?
+    }
+
+    async fn close(&mut self) -> Result<(), SinkError> {
+        let writer = self.writer.clone();
+        task::spawn_blocking(move || writer.lock().unwrap().finish()).await?
+    }
+}
+
+/// Writes every telegram in the archive files to `sink` as the meter
+/// labelled `meter`, keeping the receive times recorded in the archive.
+/// Returns the number of telegrams written.
+pub async fn export_archive(
+    paths: &[PathBuf],
+    meter: Option<&str>,
+    sink: &mut dyn Sink,
+) -> Result<usize, SinkError> {
+    let mut count = 0;
+    for path in paths {
+        let reader = open_archive_file(path)?;
+        info!("Exporting {}", path.display());
+        let (sender, mut telegrams) = mpsc::channel(READ_AHEAD);
+        let path = path.clone();
+        task::spawn_blocking(move || read_archive(&path, reader, sender));
+        while let Some((received, lines)) = telegrams.recv().await {
+            let mut data = match deserialise_p1_message(&lines) {
+                Ok(data) => data,
+                Err(e) => {
+                    warn!("Skipping telegram that could not be parsed: {}", e);
+                    continue;
+                }
+            };
+            data.received = received;
+            data.lines = lines;
+            sink.write(meter, &data).await?;
+            count += 1;
+        }
+    }
+    sink.close().await?;
+    Ok(count)
+}
+
+/// Reads an archive file one telegram at a time, together with the receive
+/// time on the `#` line before it.
+fn read_archive(
+    path: &Path,
+    reader: Box<dyn BufRead + Send>,
+    sender: Sender<(Option<DateTime<Utc>>, Vec<String>)>,
+) {
+    let mut received = None;
+    let mut telegram = Vec::new();
+    for line in reader.lines() {
+        let line = match line {
+            Ok(line) => line,
+            Err(e) => return error!("Unable to read {}: {}", path.display(), e),
+        };
+        if let Some(time) = line.strip_prefix('#') {
+            received = DateTime::parse_from_rfc3339(time)
+                .ok()
+                .map(|t| t.with_timezone(&Utc));
+        } else if line.starts_with('/') {
+            telegram = vec![line];
+        } else if !telegram.is_empty() {
+            let end = line.starts_with('!');
+            telegram.push(line);
+            if end
+                && sender
+                    .blocking_send((received.take(), std::mem::take(&mut telegram)))
+                    .is_err()
+            {
+                return;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{sample_text, sample_usage};
+    use parquet::arrow::arrow_reader::ParquetRecordBatchReaderBuilder;
+    use std::env;
+
+    fn archive(directory: &Path) -> PathBuf {
+        let mut text = String::new();
+        for (received, time) in [
+            ("2020-12-21T11:08:33.512Z", "201221120833W"),
+            ("2020-12-22T11:08:33.512Z", "201222120833W"),
+        ] {
+            text.push_str(&format!("#{}\r\n", received));
+            text.push_str(&sample_text().replace("201221010833W", time));
+        }
+        let path = directory.join("telegrams-2020-12-21.txt");
+        fs::write(&path, text).unwrap();
+        path
+    }
+
+    #[tokio::test]
+    async fn test_export_csv() {
+        let directory = env::temp_dir().join("energise_test_export_csv");
+        fs::remove_dir_all(&directory).ok();
+        fs::create_dir_all(&directory).unwrap();
+        let path = archive(&directory);
+        let export = Export {
+            directory: directory.join("csv"),
+            format: Format::Csv,
+            rotation: Rotation::Daily,
+        };
+        let count = export_archive(&[path], Some("house"), &mut export.sink())
+            .await
+            .unwrap();
+        assert_eq!(count, 2);
+
+        let csv = fs::read_to_string(directory.join("csv/readings-2020-12-21.csv")).unwrap();
+        let mut lines = csv.lines();
+        assert_eq!(
+            lines.next().unwrap(),
+            "meter,received,electricity_timestamp,electricity_reading_low_tariff,\
+             electricity_reading_normal_tariff,electricity_returned_reading_low_tariff,\
+             electricity_returned_reading_normal_tariff,power_receiving,power_returning,\
+             voltage,current,gas_reading,gas_timestamp"
+        );
+        assert_eq!(
+            lines.next().unwrap(),
+            "house,2020-12-21T11:08:33Z,2020-12-21T11:08:33Z,2134.177,3448.211,0,0,0.229,0,\
+             236.7,1,3799.479,2020-12-21T00:05:11Z"
+        );
+        assert_eq!(lines.next(), None);
+        assert!(directory.join("csv/readings-2020-12-22.csv").exists());
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_export_parquet() {
+        let directory = env::temp_dir().join("energise_test_export_parquet");
+        fs::remove_dir_all(&directory).ok();
+        fs::create_dir_all(&directory).unwrap();
+        let path = archive(&directory);
+        let export = Export {
+            directory: directory.join("parquet"),
+            format: Format::Parquet,
+            rotation: Rotation::Monthly,
+        };
+        export_archive(&[path], None, &mut export.sink())
+            .await
+            .unwrap();
+
+        let file = File::open(directory.join("parquet/readings-2020-12.parquet")).unwrap();
+        let reader = ParquetRecordBatchReaderBuilder::try_new(file)
+            .unwrap()
+            .build()
+            .unwrap();
+        let batches: Vec<RecordBatch> = reader.map(Result::unwrap).collect();
+        assert_eq!(batches.iter().map(|b| b.num_rows()).sum::<usize>(), 2);
+        let schema = batches[0].schema();
+        assert_eq!(schema.fields().len(), 13);
+        assert_eq!(
+            schema
+                .field_with_name("power_receiving")
+                .unwrap()
+                .data_type(),
+            &DataType::Float64
+        );
+        let power = batches[0]
+            .column_by_name("power_receiving")
+            .unwrap()
+            .as_any()
+            .downcast_ref::<Float64Array>()
+            .unwrap();
+        assert_eq!(power.value(0), 0.229);
+
+        // Until it is finished a file keeps a temporary name, and a restart
+        // starts a new file
+        let data = sample_usage();
+        let path = directory.join("parquet/readings-2020-12-1.parquet");
+        let mut sink = export.sink();
+        sink.write(None, &data).await.unwrap();
+        sink.flush().await.unwrap();
+        assert!(temporary(&path).exists());
+        assert!(!path.exists());
+        sink.close().await.unwrap();
+        assert!(!temporary(&path).exists());
+        assert!(path.exists());
+        fs::remove_dir_all(&directory).unwrap();
+    }
+}
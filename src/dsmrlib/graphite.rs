@@ -0,0 +1,259 @@
+use crate::{usage_to_points, InfluxSchema, Sink, SinkError, TimestampSource, UsageData};
+use async_trait::async_trait;
+use chrono::Utc;
+use influx_db_client::{Point, Value};
+use log::info;
+use std::time::Duration;
+use tokio::io::AsyncWriteExt;
+use tokio::net::TcpStream;
+use tokio::time::{timeout, Instant};
+
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Tags of the points of `usage_to_points` in the default schema that can be
+/// used in a path.
+const PLACEHOLDERS: [&str; 4] = ["meter", "energy_type", "reading", "unit"];
+
+/// Writes readings to Graphite over TCP in the Carbon plaintext protocol.
+///
+/// Every point of `usage_to_points` becomes a metric, its path rendered from
+/// `template` with the tags of the point filled in. Aggregated readings also
+/// get `.min` and `.max` metrics.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Graphite {
+    /// Carbon receiver as `host:port`, usually port 2003.
+    pub address: String,
+    /// Metric path with `<meter>`, `<energy_type>`, `<reading>` and `<unit>`
+    /// placeholders. Characters other than letters, digits, `-` and `_` are
+    /// replaced in the values, and path segments left empty are dropped, so
+    /// `<meter>` disappears for an unlabelled meter.
+    pub template: String,
+    /// Number of metrics sent at once.
+    pub batch_size: usize,
+    /// Longest time metrics are buffered before they are sent.
+    pub flush_interval: Duration,
+}
+
+impl Graphite {
+    pub fn new(address: &str) -> Self {
+        Graphite {
+            address: address.to_string(),
+            template: "energise.<meter>.<energy_type>.<reading>".to_string(),
+            batch_size: 500,
+            flush_interval: Duration::from_secs(10),
+        }
+    }
+
+    /// Checks the template only has known placeholders.
+    pub fn validate(&self) -> Result<(), String> {
+        let mut rest = self.template.clone();
+        for placeholder in PLACEHOLDERS.iter() {
+            rest = rest.replace(&format!("<{}>", placeholder), "");
+        }
+        if rest.contains('<') || rest.contains('>') {
+            return Err(format!(
+                "Invalid Graphite template {}, placeholders are {}",
+                self.template,
+                PLACEHOLDERS.map(|p| format!("<{}>", p)).join(", ")
+            ));
+        }
+        Ok(())
+    }
+
+    /// Connects on the first write.
+    pub fn sink(&self, timestamps: TimestampSource) -> GraphiteSink {
+        GraphiteSink {
+            graphite: self.clone(),
+            timestamps,
+            stream: None,
+            pending: Vec::new(),
+            oldest: None,
+        }
+    }
+}
+
+pub struct GraphiteSink {
+    graphite: Graphite,
+    timestamps: TimestampSource,
+    stream: Option<TcpStream>,
+    /// Lines waiting for the batch to fill.
+    pending: Vec<String>,
+    /// When the oldest pending line was buffered.
+    oldest: Option<Instant>,
+}
+
+fn sanitise(value: &str) -> String {
+    value
+        .chars()
+        .map(|c| match c {
+            'a'..='z' | 'A'..='Z' | '0'..='9' | '-' | '_' => c,
+            _ => '_',
+        })
+        .collect()
+}
+
+impl GraphiteSink {
+    fn path(&self, point: &Point) -> String {
+        let mut path = self.graphite.template.clone();
+        for placeholder in PLACEHOLDERS.iter() {
+            let value = match point.tags.get(*placeholder) {
+                Some(Value::String(value)) => sanitise(value),
+                _ => String::new(),
+            };
+            path = path.replace(&format!("<{}>", placeholder), &value);
+        }
+        path.split('.')
+            .filter(|segment| !segment.is_empty())
+            .collect::<Vec<_>>()
+            .join(".")
+    }
+
+    fn lines(&self, meter: Option<&str>, data: &UsageData) -> Result<Vec<String>, SinkError> {
+        let points = usage_to_points(data, meter, self.timestamps, &InfluxSchema::default())
+            .map_err(|e| format!("Unable to convert telegram to points: {:?}", e))?;
+        let mut lines = Vec::new();
+        for point in &points.point {
+            let path = self.path(point);
+            let timestamp = point.timestamp.unwrap_or_else(|| Utc::now().timestamp());
+            for (field, suffix) in [("value", ""), ("min", ".min"), ("max", ".max")] {
+                if let Some(Value::Float(value)) = point.fields.get(field) {
+                    lines.push(format!("{}{} {} {}\n", path, suffix, value, timestamp));
+                }
+            }
+        }
+        Ok(lines)
+    }
+
+    async fn send(&mut self, lines: &[String]) -> Result<(), SinkError> {
+        let stream = match self.stream.as_mut() {
+            Some(stream) => stream,
+            None => {
+                let stream = timeout(TIMEOUT, TcpStream::connect(&self.graphite.address))
+                    .await
+                    .map_err(|_| format!("Timed out connecting to {}", self.graphite.address))??;
+                info!("Connected to Graphite at {}", self.graphite.address);
+                self.stream.insert(stream)
+            }
+        };
+        let written = timeout(TIMEOUT, stream.write_all(lines.concat().as_bytes())).await;
+        match written {
+            Ok(Ok(())) => Ok(()),
+            result => {
+                // Reconnect on the next attempt
+                self.stream = None;
+                match result {
+                    Ok(Err(e)) => Err(e.into()),
+                    _ => Err(format!("Timed out writing to {}", self.graphite.address).into()),
+                }
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl Sink for GraphiteSink {
+    fn name(&self) -> String {
+        format!("Graphite {}", self.graphite.address)
+    }
+
+    async fn write(&mut self, meter: Option<&str>, data: &UsageData) -> Result<(), SinkError> {
+        let mut lines = self.lines(meter, data)?;
+        let oldest = *self.oldest.get_or_insert_with(Instant::now);
+        if self.pending.len() + lines.len() < self.graphite.batch_size
+            && oldest.elapsed() < self.graphite.flush_interval
+        {
+            self.pending.append(&mut lines);
+            return Ok(());
+        }
+        // Only buffered once sent, so a retried write doesn't add its lines twice
+        let mut batch = self.pending.clone();
+        batch.append(&mut lines);
+        self.send(&batch).await?;
+        self.pending.clear();
+        self.oldest = None;
+        Ok(())
+    }
+
+    async fn flush(&mut self) -> Result<(), SinkError> {
+        match self.oldest {
+            Some(oldest) if oldest.elapsed() >= self.graphite.flush_interval => {}
+            _ => return Ok(()),
+        }
+        if !self.pending.is_empty() {
+            let batch = self.pending.clone();
+            self.send(&batch).await?;
+            self.pending.clear();
+        }
+        self.oldest = None;
+        Ok(())
+    }
+
+    async fn close(&mut self) -> Result<(), SinkError> {
+        if !self.pending.is_empty() {
+            let batch = std::mem::take(&mut self.pending);
+            self.send(&batch).await?;
+        }
+        if let Some(mut stream) = self.stream.take() {
+            stream.shutdown().await?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sample_usage;
+    use tokio::io::AsyncReadExt;
+    use tokio::net::TcpListener;
+
+    #[tokio::test]
+    async fn test_graphite() {
+        let data = sample_usage();
+        // Nothing listens on the address yet
+        let address = TcpListener::bind("127.0.0.1:0")
+            .await
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let mut graphite = Graphite::new(&address.to_string());
+        graphite.batch_size = 10;
+        let mut sink = graphite.sink(TimestampSource::Meter);
+        sink.write(Some("flat 1"), &data).await.unwrap();
+        assert!(sink.write(Some("flat 1"), &data).await.is_err());
+
+        let listener = TcpListener::bind(address).await.unwrap();
+        sink.write(Some("flat 1"), &data).await.unwrap();
+        sink.close().await.unwrap();
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let mut received = String::new();
+        stream.read_to_string(&mut received).await.unwrap();
+        let lines: Vec<&str> = received.lines().collect();
+        assert_eq!(lines.len(), 18);
+        assert_eq!(
+            lines[0],
+            "energise.flat_1.electricity.low_tariff 2134.177 1608509313"
+        );
+        assert!(lines.contains(&"energise.flat_1.gas.receiving 3799.479 1608509111"));
+
+        // Lines that don't fill a batch are sent once they have waited long enough
+        graphite.batch_size = 100;
+        graphite.flush_interval = Duration::from_millis(100);
+        let mut sink = graphite.sink(TimestampSource::Meter);
+        sink.write(Some("flat 1"), &data).await.unwrap();
+        sink.flush().await.unwrap();
+        assert!(timeout(Duration::from_millis(10), listener.accept())
+            .await
+            .is_err());
+        tokio::time::sleep(graphite.flush_interval).await;
+        sink.flush().await.unwrap();
+        let (mut stream, _) = listener.accept().await.unwrap();
+        sink.close().await.unwrap();
+        let mut received = String::new();
+        stream.read_to_string(&mut received).await.unwrap();
+        assert_eq!(received.lines().count(), 9);
+
+        graphite.template = "<site>.<reading>".to_string();
+        assert!(graphite.validate().is_err());
+    }
+}
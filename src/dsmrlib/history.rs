@@ -0,0 +1,393 @@
+use crate::ndjson::Telegram;
+use crate::{Measurement, Phase, Reading, Sink, SinkError, UsageData};
+use async_trait::async_trait;
+use chrono::{DateTime, Duration, TimeZone, Utc};
+use serde::Serialize;
+use serde_json::Value;
+use std::collections::{BTreeMap, VecDeque};
+use std::sync::{Arc, Mutex};
+
+/// Seconds of power averaged into one point of the dashboard chart.
+const CHART_INTERVAL: i64 = 300;
+
+/// Meter readings that only count up, of which the dashboard shows usage.
+const TOTALS: [&str; 5] = [
+    "electricity_reading_low_tariff",
+    "electricity_reading_normal_tariff",
+    "electricity_returned_reading_low_tariff",
+    "electricity_returned_reading_normal_tariff",
+    "gas_reading",
+];
+
+/// Readings of one telegram, in the order of `UsageData::readings`.
+#[derive(Debug, Clone)]
+struct Entry {
+    received: DateTime<Utc>,
+    electricity_time: DateTime<Utc>,
+    gas_time: DateTime<Utc>,
+    values: [Option<f64>; 9],
+}
+
+#[derive(Debug)]
+struct MeterHistory {
+    latest: UsageData,
+    entries: VecDeque<Entry>,
+}
+
+/// A meter as listed by the API.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct MeterInfo {
+    pub meter: String,
+    pub equipment_id: Option<String>,
+    /// Telegrams held in the history.
+    pub telegrams: usize,
+    pub last_received: DateTime<Utc>,
+}
+
+/// Values of one field of a meter, as `[time, value]` pairs.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Series {
+    pub meter: String,
+    pub field: String,
+    pub unit: String,
+    pub values: Vec<(DateTime<Utc>, f64)>,
+}
+
+/// What the dashboard shows of a meter.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Summary {
+    pub meter: String,
+    pub received: DateTime<Utc>,
+    pub power_receiving: Option<f64>,
+    pub power_returning: Option<f64>,
+    pub phases: Vec<Phase>,
+    /// Start of `usage`, the requested time or the oldest telegram held when
+    /// that is later.
+    pub usage_since: Option<DateTime<Utc>>,
+    /// Usage of every meter reading since `usage_since`, by field.
+    pub usage: BTreeMap<&'static str, Measurement>,
+    /// Mean power received and returned per five minutes of the history, as
+    /// `[time, receiving, returning]`.
+    pub chart: Vec<(DateTime<Utc>, Option<f64>, Option<f64>)>,
+}
+
+/// Recent telegrams of every meter, kept in memory for the REST API.
+/// Clones share their state, so one clone can be written to as a sink while
+/// another is served.
+///
+/// Telegrams older than `period` are dropped, as are the oldest ones beyond
+/// one per second of it, which bounds the memory used by replays.
+#[derive(Debug, Clone)]
+pub struct History {
+    pub period: Duration,
+    meters: Arc<Mutex<BTreeMap<String, MeterHistory>>>,
+}
+
+impl Entry {
+    fn time(&self, energy_type: &str) -> DateTime<Utc> {
+        if energy_type == "gas" {
+            self.gas_time
+        } else {
+            self.electricity_time
+        }
+    }
+}
+
+/// `meter`, or whichever meter sent a telegram last.
+fn select<'a>(
+    meters: &'a BTreeMap<String, MeterHistory>,
+    meter: Option<&str>,
+) -> Option<(&'a String, &'a MeterHistory)> {
+    match meter {
+        Some(meter) => meters.get_key_value(meter),
+        None => meters
+            .iter()
+            .max_by_key(|(_, history)| history.latest.received),
+    }
+}
+
+fn value(reading: &Reading) -> Option<f64> {
+    match reading {
+        Reading::Measurement(m) => Some(m.value),
+        Reading::Timestamp(_) => None,
+    }
+}
+
+impl History {
+    pub fn new(period: Duration) -> Self {
+        History {
+            period,
+            meters: Arc::new(Mutex::new(BTreeMap::new())),
+        }
+    }
+
+    /// The most recent full telegram of `meter`, or of whichever meter sent
+    /// one last.
+    pub fn latest(&self, meter: Option<&str>) -> Option<Value> {
+        let meters = self.meters.lock().unwrap();
+        let (label, history) = select(&meters, meter)?;
+        serde_json::to_value(Telegram {
+            meter: Some(label),
+            data: &history.latest,
+        })
+        .ok()
+    }
+
+    pub fn meters(&self) -> Vec<MeterInfo> {
+        let meters = self.meters.lock().unwrap();
+        meters
+            .iter()
+            .map(|(meter, history)| MeterInfo {
+                meter: meter.clone(),
+                equipment_id: history.latest.equipment_id(),
+                telegrams: history.entries.len(),
+                last_received: history.latest.received.unwrap_or_else(Utc::now),
+            })
+            .collect()
+    }
+
+    /// Values of `field` since `since`, of `meter` or of every meter. Time is
+    /// the meter's own, the capture time of the M-Bus value for gas.
+    pub fn series(
+        &self,
+        field: &str,
+        since: Option<DateTime<Utc>>,
+        meter: Option<&str>,
+    ) -> Result<Vec<Series>, String> {
+        let meters = self.meters.lock().unwrap();
+        let mut series = Vec::new();
+        for (label, history) in meters.iter() {
+            if meter.is_some_and(|meter| meter != label) {
+                continue;
+            }
+            let readings = history.latest.readings();
+            let (index, (energy_type, _, reading)) = readings
+                .iter()
+                .enumerate()
+                .find(|(_, (_, name, _))| *name == field)
+                .ok_or_else(|| format!("Unknown field: {}", field))?;
+            let unit = match reading {
+                Reading::Measurement(m) => m.unit.clone(),
+                Reading::Timestamp(_) => String::new(),
+            };
+            let values = history
+                .entries
+                .iter()
+                .filter_map(|entry| {
+                    let time = entry.time(energy_type);
+                    match (since, entry.values[index]) {
+                        (Some(since), _) if time < since => None,
+                        (_, value) => Some((time, value?)),
+                    }
+                })
+                .collect();
+            series.push(Series {
+                meter: label.clone(),
+                field: field.to_string(),
+                unit,
+                values,
+            });
+        }
+        Ok(series)
+    }
+
+    /// Current power, usage since `since` and the power over the history of
+    /// `meter`, or of whichever meter sent a telegram last.
+    pub fn summary(&self, meter: Option<&str>, since: DateTime<Utc>) -> Option<Summary> {
+        let meters = self.meters.lock().unwrap();
+        let (label, history) = select(&meters, meter)?;
+        let latest = &history.latest;
+        let readings = latest.readings();
+        let index = |field: &str| readings.iter().position(|(_, name, _)| *name == field);
+
+        let mut usage = BTreeMap::new();
+        let mut usage_since = None;
+        for (field, index) in TOTALS.iter().filter_map(|f| Some((*f, index(f)?))) {
+            let (energy_type, _, reading) = readings[index];
+            let current = match reading {
+                Reading::Measurement(m) => m,
+                Reading::Timestamp(_) => continue,
+            };
+            let first = history
+                .entries
+                .iter()
+                .find(|entry| entry.time(energy_type) >= since && entry.values[index].is_some());
+            if let Some(first) = first {
+                if energy_type == "electricity" {
+                    usage_since = Some(first.time(energy_type));
+                }
+                usage.insert(
+                    field,
+                    Measurement {
+                        value: current.value - first.values[index].unwrap_or_default(),
+                        unit: current.unit.clone(),
+                        min: None,
+                        max: None,
+                    },
+                );
+            }
+        }
+
+        let (receiving, returning) = (index("power_receiving")?, index("power_returning")?);
+        let mut buckets: BTreeMap<i64, [(f64, usize); 2]> = BTreeMap::new();
+        for entry in &history.entries {
+            let time = entry.electricity_time.timestamp();
+            let bucket = buckets
+                .entry(time - time.rem_euclid(CHART_INTERVAL))
+                .or_default();
+            for (sum, value) in bucket
+                .iter_mut()
+                .zip([entry.values[receiving], entry.values[returning]])
+            {
+                if let Some(value) = value {
+                    *sum = (sum.0 + value, sum.1 + 1);
+                }
+            }
+        }
+        let mean = |(sum, count): (f64, usize)| (count > 0).then(|| sum / count as f64);
+        let chart = buckets
+            .into_iter()
+            .filter_map(|(time, [receiving, returning])| {
+                Some((
+                    Utc.timestamp_opt(time, 0).single()?,
+                    mean(receiving),
+                    mean(returning),
+                ))
+            })
+            .collect();
+
+        Some(Summary {
+            meter: label.clone(),
+            received: latest.received.unwrap_or_else(Utc::now),
+            power_receiving: value(&latest.power_receiving),
+            power_returning: value(&latest.power_returning),
+            phases: latest.phases(),
+            usage_since,
+            usage,
+            chart,
+        })
+    }
+}
+
+#[async_trait]
+impl Sink for History {
+    fn name(&self) -> String {
+        "history".to_string()
+    }
+
+    async fn write(&mut self, meter: Option<&str>, data: &UsageData) -> Result<(), SinkError> {
+        let received = data.received.unwrap_or_else(Utc::now);
+        let time = |reading: &Reading| match reading {
+            Reading::Timestamp(t) => t.timestamp.with_timezone(&Utc),
+            Reading::Measurement(_) => received,
+        };
+        let mut values = [None; 9];
+        for (value, (_, _, reading)) in values.iter_mut().zip(data.readings().iter()) {
+            if let Reading::Measurement(m) = reading {
+                *value = Some(m.value);
+            }
+        }
+        let entry = Entry {
+            received,
+            electricity_time: time(&data.electricity_timestamp),
+            gas_time: time(&data.gas_timestamp),
+            values,
+        };
+
+        let mut meters = self.meters.lock().unwrap();
+        let history = meters
+            .entry(meter.unwrap_or("meter").to_string())
+            .or_insert_with(|| MeterHistory {
+                latest: data.clone(),
+                entries: VecDeque::new(),
+            });
+        history.latest = data.clone();
+        history.latest.received = Some(received);
+        history.entries.push_back(entry);
+        let oldest = received - self.period;
+        let capacity = self.period.num_seconds().max(1) as usize;
+        while history
+            .entries
+            .front()
+            .is_some_and(|entry| entry.received < oldest)
+            || history.entries.len() > capacity
+        {
+            history.entries.pop_front();
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{deserialise_p1_message, sample_lines};
+    use chrono::TimeZone;
+
+    fn telegram(time: &str, power: &str) -> UsageData {
+        let mut lines = sample_lines();
+        lines[0] = format!("0-0:1.0.0({}W)", time);
+        lines[5] = format!("1-0:1.7.0({}*kW)", power);
+        let mut data = deserialise_p1_message(&lines).unwrap();
+        data.received = Some(Utc::now());
+        data
+    }
+
+    #[tokio::test]
+    async fn test_history() {
+        let mut history = History::new(Duration::seconds(2));
+        history
+            .write(Some("house"), &telegram("201221010831", "00.100"))
+            .await
+            .unwrap();
+        history
+            .write(Some("house"), &telegram("201221010832", "00.200"))
+            .await
+            .unwrap();
+        history
+            .write(Some("house"), &telegram("201221010833", "00.300"))
+            .await
+            .unwrap();
+        history
+            .write(Some("flat"), &telegram("201221010833", "01.000"))
+            .await
+            .unwrap();
+
+        assert_eq!(history.latest(None).unwrap()["meter"], "flat");
+        let latest = history.latest(Some("house")).unwrap();
+        assert_eq!(latest["power_receiving"]["value"], 0.3);
+        assert!(history.latest(Some("shed")).is_none());
+        let meters = history.meters();
+        assert_eq!(meters.len(), 2);
+        assert_eq!(
+            (meters[1].meter.as_str(), meters[1].telegrams),
+            ("house", 2)
+        );
+
+        let since = Utc.with_ymd_and_hms(2020, 12, 21, 0, 8, 33).unwrap();
+        let series = history
+            .series("power_receiving", Some(since), Some("house"))
+            .unwrap();
+        assert_eq!(
+            series,
+            vec![Series {
+                meter: "house".to_string(),
+                field: "power_receiving".to_string(),
+                unit: "kW".to_string(),
+                values: vec![(since, 0.3)],
+            }]
+        );
+        assert_eq!(history.series("gas_reading", None, None).unwrap().len(), 2);
+        assert!(history.series("power", None, None).is_err());
+
+        let summary = history.summary(Some("house"), since).unwrap();
+        assert_eq!(summary.power_receiving, Some(0.3));
+        assert_eq!(summary.usage_since, Some(since));
+        assert_eq!(summary.usage["electricity_reading_low_tariff"].value, 0.0);
+        // Gas was last read before `since`
+        assert!(!summary.usage.contains_key("gas_reading"));
+        let time = Utc.with_ymd_and_hms(2020, 12, 21, 0, 5, 0).unwrap();
+        assert_eq!(summary.chart, vec![(time, Some(0.25), Some(0.0))]);
+        assert!(history.summary(Some("shed"), since).is_none());
+    }
+}
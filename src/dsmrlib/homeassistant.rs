@@ -0,0 +1,130 @@
+//! Home Assistant MQTT discovery, so readings show up as sensors of one
+//! device per meter and can be used in the Energy dashboard.
+
+use crate::{Reading, UsageData};
+use serde_json::{json, Value};
+
+/// Device and state class of a reading, cumulative registers are
+/// `total_increasing` so Home Assistant derives usage from them.
+fn classes(name: &str) -> (&'static str, &'static str) {
+    match name {
+        "power_receiving" | "power_returning" => ("power", "measurement"),
+        "voltage" => ("voltage", "measurement"),
+        "current" => ("current", "measurement"),
+        "gas_reading" => ("gas", "total_increasing"),
+        _ => ("energy", "total_increasing"),
+    }
+}
+
+/// Units as Home Assistant expects them for each device class.
+fn unit(unit: &str) -> &str {
+    match unit {
+        "m3" => "m³",
+        unit => unit,
+    }
+}
+
+fn friendly_name(name: &str) -> String {
+    let name = name.replace('_', " ");
+    let mut chars = name.chars();
+    match chars.next() {
+        Some(first) => first.to_uppercase().chain(chars).collect(),
+        None => name,
+    }
+}
+
+/// Discovery config topics and payloads for every reading in `data`, with
+/// states published under `state_topic`. The device is named after the
+/// equipment identifier, or `meter` when the telegram has none.
+pub(crate) fn discovery_configs(
+    discovery_prefix: &str,
+    state_topic: &str,
+    meter: &str,
+    data: &UsageData,
+) -> Vec<(String, Value)> {
+    let name = data.equipment_id().unwrap_or_else(|| meter.to_string());
+    let node_id: String = format!("energise_{}", name)
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+        .collect();
+    let mut device = json!({
+        "identifiers": [node_id],
+        "name": name,
+        "manufacturer": "energise",
+    });
+    if let Some(header) = data.lines.first().and_then(|l| l.strip_prefix('/')) {
+        device["model"] = json!(header);
+    }
+
+    data.readings()
+        .iter()
+        .filter_map(|(energy_type, reading_name, reading)| match reading {
+            Reading::Measurement(m) => {
+                let (device_class, state_class) = classes(reading_name);
+                let config = json!({
+                    "name": friendly_name(reading_name),
+                    "unique_id": format!("{}_{}", node_id, reading_name),
+                    "state_topic": format!("{}/{}/{}", state_topic, energy_type, reading_name),
+                    "device_class": device_class,
+                    "state_class": state_class,
+                    "unit_of_measurement": unit(&m.unit),
+                    "device": device,
+                });
+                let topic = format!(
+                    "{}/sensor/{}/{}/config",
+                    discovery_prefix, node_id, reading_name
+                );
+                Some((topic, config))
+            }
+            Reading::Timestamp(_) => None,
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{deserialise_p1_message, sample_telegram};
+
+    #[test]
+    fn test_discovery_configs() {
+        let mut lines = sample_telegram();
+        // The equipment identifier names the device
+        lines.insert(
+            3,
+            "0-0:96.1.1(4530303433303036393939363136373137)".to_string(),
+        );
+        let mut data = deserialise_p1_message(&lines).unwrap();
+        data.lines = lines;
+        let configs = discovery_configs("homeassistant", "energise/house", "house", &data);
+        assert_eq!(configs.len(), 9);
+        let config = |name: &str| {
+            configs
+                .iter()
+                .find(|(topic, _)| topic.ends_with(&format!("/{}/config", name)))
+                .unwrap()
+        };
+
+        let (topic, power) = config("power_receiving");
+        assert_eq!(
+            topic,
+            "homeassistant/sensor/energise_E0043006999616717/power_receiving/config"
+        );
+        assert_eq!(power["state_class"], "measurement");
+        assert_eq!(power["device_class"], "power");
+        assert_eq!(power["unit_of_measurement"], "kW");
+        assert_eq!(
+            power["state_topic"],
+            "energise/house/electricity/power_receiving"
+        );
+        assert_eq!(power["device"]["name"], "E0043006999616717");
+
+        let (_, low_tariff) = config("electricity_reading_low_tariff");
+        assert_eq!(low_tariff["state_class"], "total_increasing");
+        assert_eq!(low_tariff["device_class"], "energy");
+        let (_, gas) = config("gas_reading");
+        assert_eq!(gas["state_class"], "total_increasing");
+        assert_eq!(gas["unit_of_measurement"], "m³");
+        assert_eq!(gas["device"], power["device"]);
+    }
+}
@@ -0,0 +1,215 @@
+use chrono::{DateTime, NaiveDateTime, Offset, TimeZone, Utc};
+use chrono_tz::Europe::Amsterdam;
+use log::{error, info};
+use reqwest::header::CONTENT_TYPE;
+use reqwest::{Client, Url};
+use serde::Deserialize;
+use std::collections::VecDeque;
+use std::time::Duration;
+use tokio::time::{interval, Interval, MissedTickBehavior};
+
+const REQUEST_TIMEOUT: u64 = 5;
+
+/// The subset of a HomeWizard P1 meter `/api/v1/data` response that maps
+/// onto the telegram model.
+#[derive(Debug, Deserialize)]
+struct HomeWizardData {
+    active_power_w: f64,
+    total_power_import_t1_kwh: f64,
+    total_power_import_t2_kwh: f64,
+    total_power_export_t1_kwh: f64,
+    total_power_export_t2_kwh: f64,
+    active_voltage_l1_v: Option<f64>,
+    active_current_l1_a: Option<f64>,
+    total_gas_m3: Option<f64>,
+    gas_timestamp: Option<u64>,
+}
+
+/// Lines polled from a HomeWizard style HTTP API. `/api/v1/telegram` returns
+/// the raw telegram as text, JSON responses such as `/api/v1/data` are turned
+/// into an equivalent telegram so the rest of the pipeline stays the same.
+pub(crate) struct HttpLines {
+    client: Client,
+    url: Url,
+    interval: Interval,
+    lines: VecDeque<String>,
+}
+
+impl HttpLines {
+    pub(crate) fn new(url: &Url, every: Duration) -> Self {
+        info!("Polling {} every {:?}", url, every);
+        let mut interval = interval(every);
+        interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        HttpLines {
+            client: Client::builder()
+                .timeout(Duration::from_secs(REQUEST_TIMEOUT))
+                .build()
+                .expect("Unable to build http client"),
+            url: url.clone(),
+            interval,
+            lines: VecDeque::new(),
+        }
+    }
+
+    async fn poll(&self) -> Result<Vec<String>, reqwest::Error> {
+        let response = self
+            .client
+            .get(self.url.clone())
+            .send()
+            .await?
+            .error_for_status()?;
+        let is_json = response
+            .headers()
+            .get(CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok())
+            .is_some_and(|value| value.starts_with("application/json"));
+        if is_json {
+            Ok(data_to_telegram(&response.json().await?, Utc::now()))
+        } else {
+            Ok(response
+                .text()
+                .await?
+                .lines()
+                .map(|l| l.to_string())
+                .collect())
+        }
+    }
+
+    pub(crate) async fn next_line(&mut self) -> Option<String> {
+        while self.lines.is_empty() {
+            self.interval.tick().await;
+            match self.poll().await {
+                Ok(lines) => self.lines.extend(lines),
+                Err(e) => error!("Unable to poll {}: {}", self.url, e),
+            }
+        }
+        self.lines.pop_front()
+    }
+}
+
+fn data_to_telegram(data: &HomeWizardData, now: DateTime<Utc>) -> Vec<String> {
+    // Meters report Dutch local time with a W(inter) or S(ummer) suffix,
+    // whatever the time zone of the host
+    let now = now.with_timezone(&Amsterdam);
+    let mut lines = vec![
+        "/HomeWizard".to_string(),
+        format!("0-0:1.0.0({}{})", now.format("%y%m%d%H%M%S"), season(&now)),
+        format!("1-0:1.8.1({:010.3}*kWh)", data.total_power_import_t1_kwh),
+        format!("1-0:1.8.2({:010.3}*kWh)", data.total_power_import_t2_kwh),
+        format!("1-0:2.8.1({:010.3}*kWh)", data.total_power_export_t1_kwh),
+        format!("1-0:2.8.2({:010.3}*kWh)", data.total_power_export_t2_kwh),
+        format!(
+            "1-0:1.7.0({:06.3}*kW)",
+            data.active_power_w.max(0.0) / 1000.0
+        ),
+        format!(
+            "1-0:2.7.0({:06.3}*kW)",
+            (-data.active_power_w).max(0.0) / 1000.0
+        ),
+    ];
+    if let Some(voltage) = data.active_voltage_l1_v {
+        lines.push(format!("1-0:32.7.0({:05.1}*V)", voltage));
+    }
+    if let Some(current) = data.active_current_l1_a {
+        lines.push(format!("1-0:31.7.0({:03.0}*A)", current));
+    }
+    if let (Some(gas), Some(timestamp)) = (data.total_gas_m3, data.gas_timestamp) {
+        // The gas reading can be from before a change of season
+        let gas_season =
+            NaiveDateTime::parse_from_str(&format!("{:012}", timestamp), "%y%m%d%H%M%S")
+                .ok()
+                .and_then(|time| Amsterdam.from_local_datetime(&time).earliest())
+                .map_or_else(|| season(&now), |time| season(&time));
+        lines.push(format!(
+            "0-1:24.2.1({:012}{})({:09.3}*m3)",
+            timestamp, gas_season, gas
+        ));
+    }
+    lines.push("!".to_string());
+    lines
+}
+
+fn season<Tz: TimeZone>(time: &DateTime<Tz>) -> char {
+    if time.offset().fix().local_minus_utc() == 7200 {
+        'S'
+    } else {
+        'W'
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::{serve, Response};
+    use crate::Input;
+
+    async fn read_lines(input: Input, count: usize) -> Vec<String> {
+        let mut lines = input.open().await.unwrap();
+        let mut read = Vec::new();
+        while read.len() < count {
+            read.push(lines.next_line().await.unwrap());
+        }
+        read
+    }
+
+    #[tokio::test]
+    async fn test_poll_telegram() {
+        let (address, _) = serve(vec![Response::ok(
+            "text/plain",
+            "/ISK5\\2M550E-1012\r\n\r\n1-0:1.7.0(00.229*kW)\r\n!5C6B\r\n",
+        )]);
+        let input: Input = format!("http://{}/api/v1/telegram?interval=1", address)
+            .parse()
+            .unwrap();
+        let lines = read_lines(input, 4).await;
+        assert_eq!(
+            lines,
+            vec!["/ISK5\\2M550E-1012", "", "1-0:1.7.0(00.229*kW)", "!5C6B"]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_poll_data() {
+        let (address, _) = serve(vec![Response::ok(
+            "application/json",
+            r#"{"wifi_ssid":"home","active_power_w":-543,"total_power_import_t1_kwh":2134.177,
+            "total_power_import_t2_kwh":3448.211,"total_power_export_t1_kwh":12.5,
+            "total_power_export_t2_kwh":0,"active_voltage_l1_v":236.7,"active_current_l1_a":1,
+            "total_gas_m3":3799.479,"gas_timestamp":201221010511}"#,
+        )]);
+        let input: Input = format!("http://{}/api/v1/data", address).parse().unwrap();
+        let lines = read_lines(input, 12).await;
+        assert_eq!(lines[0], "/HomeWizard");
+        assert_eq!(lines[2], "1-0:1.8.1(002134.177*kWh)");
+        assert_eq!(lines[6], "1-0:1.7.0(00.000*kW)");
+        assert_eq!(lines[7], "1-0:2.7.0(00.543*kW)");
+        assert_eq!(lines[8], "1-0:32.7.0(236.7*V)");
+        assert_eq!(lines[9], "1-0:31.7.0(001*A)");
+        assert!(lines[10].starts_with("0-1:24.2.1(201221010511"));
+        assert!(lines[10].ends_with("(03799.479*m3)"));
+        assert_eq!(lines[11], "!");
+    }
+
+    #[test]
+    fn test_telegram_time() {
+        let data: HomeWizardData = serde_json::from_str(
+            r#"{"active_power_w":229,"total_power_import_t1_kwh":2134.177,
+            "total_power_import_t2_kwh":3448.211,"total_power_export_t1_kwh":0,
+            "total_power_export_t2_kwh":0,"active_current_l1_a":1.6,
+            "total_gas_m3":3799.479,"gas_timestamp":210328015500}"#,
+        )
+        .unwrap();
+        // Independent of the zone of the host, UTC in most containers
+        let winter = Utc.with_ymd_and_hms(2020, 12, 21, 0, 8, 33).unwrap();
+        assert_eq!(
+            data_to_telegram(&data, winter)[1],
+            "0-0:1.0.0(201221010833W)"
+        );
+        let summer = Utc.with_ymd_and_hms(2021, 6, 21, 10, 0, 0).unwrap();
+        let lines = data_to_telegram(&data, summer);
+        assert_eq!(lines[1], "0-0:1.0.0(210621120000S)");
+        assert_eq!(lines[8], "1-0:31.7.0(002*A)");
+        // Read just before the clocks went forward
+        assert_eq!(lines[9], "0-1:24.2.1(210328015500W)(03799.479*m3)");
+    }
+}
@@ -0,0 +1,392 @@
+use crate::live::Subscription;
+use crate::watchdog::{Liveness, WatchdogStatus};
+use crate::{History, Live, Metrics};
+use axum::extract::ws::WebSocketUpgrade;
+use axum::extract::Query;
+use axum::http::header::CONTENT_TYPE;
+use axum::http::StatusCode;
+use axum::routing::get;
+use axum::{Json, Router, Server};
+use chrono::{DateTime, Local, NaiveTime, TimeZone, Utc};
+use log::{error, info};
+use serde::{Deserialize, Serialize};
+use std::error::Error;
+use std::net::SocketAddr;
+
+const OPENMETRICS: &str = "application/openmetrics-text; version=1.0.0; charset=utf-8";
+
+const DASHBOARD: &str = include_str!("dashboard.html");
+
+/// HTTP server for pulling data out of energise, serving `/metrics` for
+/// Prometheus and `/api/v1/health` when `metrics` is set, a WebSocket stream of telegrams on
+/// `/ws` when `live` is set, and the REST API under `/api/v1` and a dashboard
+/// on `/` when `history` is set.
+#[derive(Debug, Clone)]
+pub struct HttpServer {
+    pub address: SocketAddr,
+    pub metrics: Option<Metrics>,
+    pub live: Option<Live>,
+    pub history: Option<History>,
+}
+
+/// Subscription of a WebSocket client, `fields` separated by commas.
+#[derive(Debug, Deserialize)]
+struct StreamQuery {
+    meter: Option<String>,
+    fields: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct MeterQuery {
+    meter: Option<String>,
+}
+
+/// Query of `/api/v1/history`, `since` as RFC 3339 or a Unix timestamp.
+#[derive(Debug, Deserialize)]
+struct HistoryQuery {
+    field: String,
+    since: Option<String>,
+    meter: Option<String>,
+}
+
+/// Query of `/api/v1/summary`, usage is since local midnight by default.
+#[derive(Debug, Deserialize)]
+struct SummaryQuery {
+    meter: Option<String>,
+    since: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+struct MeterHealth {
+    meter: String,
+    #[serde(flatten)]
+    status: WatchdogStatus,
+}
+
+/// Liveness of every meter, unhealthy when any of them stalled.
+#[derive(Debug, Serialize)]
+struct Health {
+    healthy: bool,
+    meters: Vec<MeterHealth>,
+}
+
+fn health(metrics: &Metrics) -> (StatusCode, Json<Health>) {
+    let meters: Vec<MeterHealth> = metrics
+        .statuses()
+        .into_iter()
+        .map(|(meter, status)| MeterHealth { meter, status })
+        .collect();
+    let healthy = meters
+        .iter()
+        .all(|m| m.status.liveness != Liveness::Stalled);
+    let status = if healthy {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+    (status, Json(Health { healthy, meters }))
+}
+
+type ApiError = (StatusCode, String);
+
+fn parse_since(since: &str) -> Result<DateTime<Utc>, ApiError> {
+    match since.parse::<i64>() {
+        Ok(seconds) => Utc.timestamp_opt(seconds, 0).single(),
+        Err(_) => DateTime::parse_from_rfc3339(since)
+            .ok()
+            .map(|since| since.with_timezone(&Utc)),
+    }
+    .ok_or_else(|| (StatusCode::BAD_REQUEST, format!("Invalid since: {}", since)))
+}
+
+fn midnight() -> DateTime<Utc> {
+    let today = Local::now().date_naive().and_time(NaiveTime::MIN);
+    Local
+        .from_local_datetime(&today)
+        .earliest()
+        .map_or_else(Utc::now, |midnight| midnight.with_timezone(&Utc))
+}
+
+fn api(history: History) -> Router {
+    let latest = history.clone();
+    let meters = history.clone();
+    let summary = history.clone();
+    Router::new()
+        .route(
+            "/api/v1/latest",
+            get(move |Query(query): Query<MeterQuery>| async move {
+                latest
+                    .latest(query.meter.as_deref())
+                    .map(Json)
+                    .ok_or_else(|| (StatusCode::NOT_FOUND, "No telegram received".to_string()))
+            }),
+        )
+        .route(
+            "/api/v1/meters",
+            get(move || async move { Json(meters.meters()) }),
+        )
+        .route(
+            "/api/v1/history",
+            get(move |Query(query): Query<HistoryQuery>| async move {
+                let since = query.since.as_deref().map(parse_since).transpose()?;
+                history
+                    .series(&query.field, since, query.meter.as_deref())
+                    .map(Json)
+                    .map_err(|e| (StatusCode::BAD_REQUEST, e))
+            }),
+        )
+        .route(
+            "/api/v1/summary",
+            get(move |Query(query): Query<SummaryQuery>| async move {
+                let since = match query.since.as_deref() {
+                    Some(since) => parse_since(since)?,
+                    None => midnight(),
+                };
+                summary
+                    .summary(query.meter.as_deref(), since)
+                    .map(Json)
+                    .ok_or_else(|| (StatusCode::NOT_FOUND, "No telegram received".to_string()))
+            }),
+        )
+        .route(
+            "/",
+            get(|| async { ([(CONTENT_TYPE, "text/html; charset=utf-8")], DASHBOARD) }),
+        )
+}
+
+impl HttpServer {
+    fn router(&self) -> Router {
+        let mut router = Router::new();
+        if let Some(metrics) = self.metrics.clone() {
+            let watched = metrics.clone();
+            router = router
+                .route(
+                    "/metrics",
+                    get(move || async move { ([(CONTENT_TYPE, OPENMETRICS)], metrics.render()) }),
+                )
+                .route(
+                    "/api/v1/health",
+                    get(move || async move { health(&watched) }),
+                );
+        }
+        if let Some(live) = self.live.clone() {
+            router = router.route(
+                "/ws",
+                get(
+                    move |ws: WebSocketUpgrade, Query(query): Query<StreamQuery>| async move {
+                        let subscription = Subscription {
+                            meter: query.meter,
+                            fields: query
+                                .fields
+                                .map(|fields| fields.split(',').map(str::to_string).collect()),
+                        };
+                        ws.on_upgrade(move |socket| async move {
+                            live.stream(socket, subscription).await
+                        })
+                    },
+                ),
+            );
+        }
+        if let Some(history) = self.history.clone() {
+            router = router.merge(api(history));
+        }
+        router
+    }
+
+    /// Starts serving in the background, returns the address listened on.
+    pub fn spawn(&self) -> Result<SocketAddr, Box<dyn Error>> {
+        let server = Server::try_bind(&self.address)?.serve(self.router().into_make_service());
+        let address = server.local_addr();
+        info!("Serving HTTP on {}", address);
+        tokio::spawn(async move {
+            if let Err(e) = server.await {
+                error!("HTTP server failed: {}", e);
+            }
+        });
+        Ok(address)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{sample_usage, Sink};
+    use futures_util::{SinkExt, StreamExt};
+    use serde_json::{json, Value};
+    use std::time::Duration;
+    use tokio::time::sleep;
+    use tokio_tungstenite::tungstenite;
+
+    #[tokio::test]
+    async fn test_metrics_endpoint() {
+        let address = HttpServer {
+            address: "127.0.0.1:0".parse().unwrap(),
+            metrics: Some(Metrics::default()),
+            live: None,
+            history: None,
+        }
+        .spawn()
+        .unwrap();
+
+        let response = reqwest::get(format!("http://{}/metrics", address))
+            .await
+            .unwrap();
+        assert_eq!(response.headers()[CONTENT_TYPE], OPENMETRICS);
+        assert!(response.text().await.unwrap().ends_with("# EOF\n"));
+        let response = reqwest::get(format!("http://{}/missing", address))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), 404);
+    }
+
+    #[tokio::test]
+    async fn test_health() {
+        let metrics = Metrics::default();
+        let watchdog = crate::Watchdog::default();
+        metrics.watch(Some("house"), watchdog.clone());
+        let address = HttpServer {
+            address: "127.0.0.1:0".parse().unwrap(),
+            metrics: Some(metrics),
+            live: None,
+            history: None,
+        }
+        .spawn()
+        .unwrap();
+
+        let url = format!("http://{}/api/v1/health", address);
+        let response = reqwest::get(&url).await.unwrap();
+        assert_eq!(response.status(), 200);
+        let health: Value = response.json().await.unwrap();
+        assert_eq!(health["healthy"], true);
+        assert_eq!(health["meters"][0]["meter"], "house");
+        assert_eq!(health["meters"][0]["liveness"], "starting");
+
+        let input = crate::Input::Tcp("localhost:8088".to_string());
+        watchdog.stalled(&input, Duration::from_secs(30));
+        let response = reqwest::get(&url).await.unwrap();
+        assert_eq!(response.status(), 503);
+        let health: Value = response.json().await.unwrap();
+        assert_eq!(health["meters"][0]["liveness"], "stalled");
+    }
+
+    #[tokio::test]
+    async fn test_live_stream() {
+        let mut live = Live::default();
+        let address = HttpServer {
+            address: "127.0.0.1:0".parse().unwrap(),
+            metrics: None,
+            live: Some(live.clone()),
+            history: None,
+        }
+        .spawn()
+        .unwrap();
+        let (mut socket, _) = tokio_tungstenite::connect_async(format!(
+            "ws://{}/ws?meter=house&fields=power_receiving",
+            address
+        ))
+        .await
+        .unwrap();
+        let data = sample_usage();
+        // The client subscribes once the upgrade has been handled
+        while live.clients() == 0 {
+            sleep(Duration::from_millis(10)).await;
+        }
+        live.write(Some("flat"), &data).await.unwrap();
+        live.write(Some("house"), &data).await.unwrap();
+        let message = socket.next().await.unwrap().unwrap();
+        let telegram: Value = serde_json::from_str(message.to_text().unwrap()).unwrap();
+        assert_eq!(
+            telegram,
+            json!({
+                "meter": "house",
+                "electricity_timestamp": "2020-12-21T01:08:33+01:00",
+                "power_receiving": {"value": 0.229, "unit": "kW"},
+            })
+        );
+
+        socket
+            .send(tungstenite::Message::Text(
+                "{\"fields\": [\"voltage\"]}".to_string(),
+            ))
+            .await
+            .unwrap();
+        sleep(Duration::from_millis(100)).await;
+        live.write(Some("flat"), &data).await.unwrap();
+        let message = socket.next().await.unwrap().unwrap();
+        let telegram: Value = serde_json::from_str(message.to_text().unwrap()).unwrap();
+        assert_eq!(telegram["meter"], "flat");
+        assert_eq!(telegram["voltage"]["value"], 236.7);
+        assert!(telegram.get("power_receiving").is_none());
+    }
+
+    #[tokio::test]
+    async fn test_api() {
+        let mut history = History::new(chrono::Duration::hours(1));
+        let address = HttpServer {
+            address: "127.0.0.1:0".parse().unwrap(),
+            metrics: None,
+            live: None,
+            history: Some(history.clone()),
+        }
+        .spawn()
+        .unwrap();
+        let get = |path: &str| reqwest::get(format!("http://{}{}", address, path));
+        assert_eq!(get("/api/v1/latest").await.unwrap().status(), 404);
+
+        let mut data = sample_usage();
+        data.received = Some(Utc::now());
+        history.write(Some("house"), &data).await.unwrap();
+        let latest: Value = get("/api/v1/latest").await.unwrap().json().await.unwrap();
+        assert_eq!(latest["meter"], "house");
+        assert_eq!(latest["voltage"], json!({"value": 236.7, "unit": "V"}));
+        let meters: Value = get("/api/v1/meters").await.unwrap().json().await.unwrap();
+        assert_eq!(meters[0]["meter"], "house");
+        assert_eq!(meters[0]["telegrams"], 1);
+        let series: Value = get("/api/v1/history?field=power_receiving&since=1608509313")
+            .await
+            .unwrap()
+            .json()
+            .await
+            .unwrap();
+        assert_eq!(
+            series,
+            json!([{
+                "meter": "house",
+                "field": "power_receiving",
+                "unit": "kW",
+                "values": [["2020-12-21T00:08:33Z", 0.229]],
+            }])
+        );
+        let response =
+            get("/api/v1/history?field=power_receiving&since=2020-12-21T01:08:34%2B01:00")
+                .await
+                .unwrap();
+        assert_eq!(
+            response.json::<Value>().await.unwrap()[0]["values"],
+            json!([])
+        );
+        let response = get("/api/v1/history?field=power&since=yesterday")
+            .await
+            .unwrap();
+        assert_eq!(response.status(), 400);
+
+        let summary: Value = get("/api/v1/summary?meter=house&since=1608509313")
+            .await
+            .unwrap()
+            .json()
+            .await
+            .unwrap();
+        assert_eq!(summary["power_receiving"], 0.229);
+        assert_eq!(
+            summary["usage"]["electricity_reading_normal_tariff"],
+            json!({"value": 0.0, "unit": "kWh"})
+        );
+        assert_eq!(summary["phases"][0]["voltage"], 236.7);
+        let response = get("/").await.unwrap();
+        assert!(response.headers()[CONTENT_TYPE]
+            .to_str()
+            .unwrap()
+            .starts_with("text/html"));
+        assert!(response.text().await.unwrap().contains("api/v1/summary"));
+    }
+}
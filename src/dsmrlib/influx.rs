@@ -0,0 +1,529 @@
+use crate::spool::{Spool, SpoolWriter};
+use crate::{usage_to_points, Reading, Sink, SinkError, TimestampSource, UsageData, READING_TAGS};
+use async_trait::async_trait;
+use influx_db_client::{Point, Points, Precision, Value};
+use log::error;
+use reqwest::header::AUTHORIZATION;
+use reqwest::{Client, StatusCode, Url};
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fmt;
+use std::time::Duration;
+
+/// Longest time a request to InfluxDB 2.x may take.
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Where points are written, the 1.x `/write` API or the `/api/v2/write`
+/// API of InfluxDB 2.x and 3.x.
+#[derive(Debug, Clone)]
+pub enum InfluxDb {
+    V1(influx_db_client::Client),
+    V2(InfluxDbV2),
+}
+
+impl InfluxDb {
+    pub async fn write_points(
+        &self,
+        points: Points,
+        precision: Precision,
+    ) -> Result<(), WriteError> {
+        match self {
+            InfluxDb::V1(client) => {
+                use influx_db_client::Error::*;
+                match client.write_points(points, Some(precision), None).await {
+                    Ok(()) => Ok(()),
+                    // 400, 401, 403 and 404 responses
+                    Err(e @ (SyntaxError(_) | InvalidCredentials(_) | DataBaseDoesNotExist(_))) => {
+                        Err(WriteError::Rejected(e.to_string()))
+                    }
+                    Err(e) => Err(WriteError::Unavailable(e.to_string())),
+                }
+            }
+            InfluxDb::V2(client) => client.write_points(&points, precision).await,
+        }
+    }
+}
+
+/// Why InfluxDB did not store a write.
+#[derive(Debug, Clone, PartialEq)]
+pub enum WriteError {
+    /// InfluxDB could not be reached, failed or asked to slow down with a 429,
+    /// the same write may succeed later.
+    Unavailable(String),
+    /// InfluxDB refused the points with a 4xx response, e.g. for a field type
+    /// conflict, writing them again fails the same way.
+    Rejected(String),
+}
+
+impl fmt::Display for WriteError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            WriteError::Unavailable(e) | WriteError::Rejected(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl Error for WriteError {}
+
+/// How readings are laid out as points. By default every reading is a point
+/// of the `dsmr` measurement with a `value` field, tagged with its
+/// `energy_type`, `reading` and `unit`, and with the meter label as `meter`
+/// when there is one.
+///
+/// A tag is left out when its name is empty. The `min` and `max` fields of
+/// aggregated readings are named after the value field, `<field>_min` and
+/// `<field>_max`, unless it is `value`. Points of the same measurement, tags
+/// and time are merged by InfluxDB, so giving readings their own field and
+/// leaving out the `reading` and `unit` tags writes one point per telegram.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct InfluxSchema {
+    pub measurement: String,
+    pub energy_type_tag: String,
+    pub reading_tag: String,
+    pub unit_tag: String,
+    pub meter_tag: String,
+    pub field: String,
+    /// Added to every point, e.g. `site` and `building`.
+    pub tags: BTreeMap<String, String>,
+    /// Overrides by field name of the telegram, e.g. `power_receiving`.
+    pub readings: BTreeMap<String, ReadingSchema>,
+}
+
+/// How one reading is written, unset values are taken from the schema.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ReadingSchema {
+    pub measurement: Option<String>,
+    /// Value of the reading tag.
+    pub name: Option<String>,
+    pub field: Option<String>,
+    /// Added to the points of this reading.
+    pub tags: BTreeMap<String, String>,
+    /// Leaves the reading out.
+    pub skip: bool,
+}
+
+impl Default for InfluxSchema {
+    fn default() -> Self {
+        InfluxSchema {
+            measurement: "dsmr".to_string(),
+            energy_type_tag: "energy_type".to_string(),
+            reading_tag: "reading".to_string(),
+            unit_tag: "unit".to_string(),
+            meter_tag: "meter".to_string(),
+            field: "value".to_string(),
+            tags: BTreeMap::new(),
+            readings: BTreeMap::new(),
+        }
+    }
+}
+
+impl InfluxSchema {
+    /// Checks the overrides are of known readings and every name is set.
+    pub fn validate(&self) -> Result<(), String> {
+        for field in self.readings.keys() {
+            if !READING_TAGS.iter().any(|(name, _)| name == field) {
+                return Err(format!(
+                    "Unknown reading {} in the InfluxDB schema, readings are {}",
+                    field,
+                    READING_TAGS.map(|(name, _)| name).join(", ")
+                ));
+            }
+        }
+        let mut names = self
+            .readings
+            .values()
+            .flat_map(|r| [&r.measurement, &r.field])
+            .flatten();
+        if self.measurement.is_empty() || self.field.is_empty() || names.any(String::is_empty) {
+            return Err("Empty measurement or field in the InfluxDB schema".to_string());
+        }
+        Ok(())
+    }
+
+    /// Point of one reading, `None` when it is left out.
+    pub(crate) fn point(
+        &self,
+        field: &str,
+        energy_type: &str,
+        reading_tag: &str,
+        reading: &Reading,
+        meter: Option<&str>,
+        timestamp: Option<i64>,
+    ) -> Option<Point> {
+        let overrides = self.readings.get(field);
+        if overrides.is_some_and(|r| r.skip) {
+            return None;
+        }
+        let get =
+            |f: fn(&ReadingSchema) -> &Option<String>| overrides.and_then(|r| f(r).as_deref());
+        let (value, unit, min, max) = match reading {
+            Reading::Measurement(m) => (m.value, m.unit.as_str(), m.min, m.max),
+            _ => (0.0, "Nothing", None, None),
+        };
+        let mut point = Point::new(get(|r| &r.measurement).unwrap_or(&self.measurement));
+        let tags = [
+            (&self.energy_type_tag, Some(energy_type)),
+            (
+                &self.reading_tag,
+                Some(get(|r| &r.name).unwrap_or(reading_tag)),
+            ),
+            (&self.unit_tag, Some(unit)),
+            (&self.meter_tag, meter),
+        ];
+        for (tag, value) in tags {
+            if let Some(value) = value.filter(|_| !tag.is_empty()) {
+                point = point.add_tag(tag.as_str(), Value::String(value.to_string()));
+            }
+        }
+        let extra = overrides.into_iter().flat_map(|r| r.tags.iter());
+        for (tag, value) in self.tags.iter().chain(extra) {
+            point = point.add_tag(tag.as_str(), Value::String(value.clone()));
+        }
+        let name = get(|r| &r.field).unwrap_or(&self.field);
+        point = point.add_field(name, Value::Float(value));
+        if let (Some(min), Some(max)) = (min, max) {
+            let (min_name, max_name) = match name {
+                "value" => ("min".to_string(), "max".to_string()),
+                name => (format!("{}_min", name), format!("{}_max", name)),
+            };
+            point = point
+                .add_field(min_name, Value::Float(min))
+                .add_field(max_name, Value::Float(max));
+        }
+        point.timestamp = timestamp;
+        Some(point)
+    }
+}
+
+/// Sink writing every telegram as points laid out by an `InfluxSchema`.
+pub struct InfluxSink {
+    influx_db: InfluxDb,
+    timestamps: TimestampSource,
+    schema: InfluxSchema,
+    spool: Option<Spool>,
+    writer: Option<SpoolWriter>,
+}
+
+impl InfluxSink {
+    /// With a `spool`, points are spooled to disk while InfluxDB is
+    /// unavailable instead of being retried and dropped.
+    pub fn new(
+        influx_db: InfluxDb,
+        timestamps: TimestampSource,
+        schema: InfluxSchema,
+        spool: Option<Spool>,
+    ) -> Self {
+        InfluxSink {
+            influx_db,
+            timestamps,
+            schema,
+            spool,
+            writer: None,
+        }
+    }
+}
+
+#[async_trait]
+impl Sink for InfluxSink {
+    fn name(&self) -> String {
+        "InfluxDB".to_string()
+    }
+
+    async fn write(&mut self, meter: Option<&str>, data: &UsageData) -> Result<(), SinkError> {
+        let points = usage_to_points(data, meter, self.timestamps, &self.schema)
+            .map_err(|e| format!("Unable to convert telegram to points: {:?}", e))?;
+        if self.writer.is_none() {
+            if let Some(spool) = self.spool.take() {
+                match spool.open().await {
+                    Ok(writer) => self.writer = Some(writer),
+                    Err(e) => error!("Unable to open spool {}: {}", spool.directory.display(), e),
+                }
+            }
+        }
+        match self.writer.as_mut() {
+            Some(writer) => {
+                writer.write(&self.influx_db, points).await;
+                Ok(())
+            }
+            None => Ok(self
+                .influx_db
+                .write_points(points, Precision::Seconds)
+                .await?),
+        }
+    }
+}
+
+/// Writer for the InfluxDB 2.x `/api/v2/write` API, authenticated with a token.
+/// InfluxDB 3.x accepts the same API.
+#[derive(Clone)]
+pub struct InfluxDbV2 {
+    url: Url,
+    org: String,
+    bucket: String,
+    token: String,
+    client: Client,
+}
+
+impl InfluxDbV2 {
+    pub fn new(url: Url, org: &str, bucket: &str, token: &str) -> Self {
+        InfluxDbV2 {
+            url,
+            org: org.to_string(),
+            bucket: bucket.to_string(),
+            token: token.to_string(),
+            client: Client::builder()
+                .timeout(TIMEOUT)
+                .build()
+                .expect("Unable to build http client"),
+        }
+    }
+
+    /// Checks that the server is up. `/ping` accepts any token, see
+    /// `check_bucket` to check the token.
+    pub async fn ping(&self) -> Result<(), Box<dyn Error + Send + Sync>> {
+        self.client
+            .get(self.url.join("ping")?)
+            .send()
+            .await?
+            .error_for_status()?;
+        Ok(())
+    }
+
+    /// Checks the token and org by looking up the bucket, returning whether
+    /// the token can see it. Buckets are never created, and a token that
+    /// only has write access to the bucket can't see it, so then a missing
+    /// bucket only shows on the first write.
+    pub async fn check_bucket(&self) -> Result<bool, Box<dyn Error + Send + Sync>> {
+        #[derive(Deserialize)]
+        struct Bucket {
+            name: String,
+        }
+        #[derive(Deserialize)]
+        struct Buckets {
+            buckets: Vec<Bucket>,
+        }
+
+        let mut url = self.url.join("api/v2/buckets")?;
+        url.query_pairs_mut()
+            .append_pair("org", &self.org)
+            .append_pair("name", &self.bucket);
+        let buckets: Buckets = self
+            .client
+            .get(url)
+            .header(AUTHORIZATION, format!("Token {}", self.token))
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+        Ok(buckets.buckets.iter().any(|b| b.name == self.bucket))
+    }
+
+    pub async fn write_points(
+        &self,
+        points: &Points,
+        precision: Precision,
+    ) -> Result<(), WriteError> {
+        let unavailable = |e: &dyn Error| WriteError::Unavailable(e.to_string());
+        let mut url = self.url.join("api/v2/write").map_err(|e| unavailable(&e))?;
+        url.query_pairs_mut()
+            .append_pair("org", &self.org)
+            .append_pair("bucket", &self.bucket)
+            .append_pair("precision", precision.to_str());
+        let response = self
+            .client
+            .post(url)
+            .header(AUTHORIZATION, format!("Token {}", self.token))
+            .body(line_protocol(points))
+            .send()
+            .await
+            .map_err(|e| unavailable(&e))?;
+        match response.status() {
+            StatusCode::NO_CONTENT | StatusCode::OK => Ok(()),
+            status => {
+                let body = response.text().await.unwrap_or_default();
+                let message = format!("InfluxDB returned {}: {}", status, body);
+                if status.is_client_error() && status != StatusCode::TOO_MANY_REQUESTS {
+                    Err(WriteError::Rejected(message))
+                } else {
+                    Err(WriteError::Unavailable(message))
+                }
+            }
+        }
+    }
+}
+
+impl fmt::Debug for InfluxDbV2 {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("InfluxDbV2")
+            .field("url", &self.url.as_str())
+            .field("org", &self.org)
+            .field("bucket", &self.bucket)
+            .finish()
+    }
+}
+
+/// Serialises points into the InfluxDB line protocol.
+pub(crate) fn line_protocol(points: &Points) -> String {
+    points
+        .point
+        .iter()
+        .map(|point| format!("{}\n", line(point)))
+        .collect()
+}
+
+fn line(point: &Point) -> String {
+    let mut line = escape(&point.measurement, &[',', ' ']);
+    let mut tags: Vec<_> = point.tags.iter().collect();
+    tags.sort_by(|a, b| a.0.cmp(b.0));
+    for (key, value) in tags {
+        let value = match value {
+            Value::String(s) => s.clone(),
+            other => field_value(other),
+        };
+        line.push_str(&format!(
+            ",{}={}",
+            escape(key, &[',', '=', ' ']),
+            escape(&value, &[',', '=', ' '])
+        ));
+    }
+    let mut fields: Vec<_> = point.fields.iter().collect();
+    fields.sort_by(|a, b| a.0.cmp(b.0));
+    let fields: Vec<String> = fields
+        .into_iter()
+        .map(|(key, value)| format!("{}={}", escape(key, &[',', '=', ' ']), field_value(value)))
+        .collect();
+    line.push(' ');
+    line.push_str(&fields.join(","));
+    if let Some(timestamp) = point.timestamp {
+        line.push_str(&format!(" {}", timestamp));
+    }
+    line
+}
+
+fn field_value(value: &Value) -> String {
+    match value {
+        Value::String(s) => format!("\"{}\"", s.replace('\\', "\\\\").replace('"', "\\\"")),
+        Value::Integer(i) => format!("{}i", i),
+        Value::Float(f) => f.to_string(),
+        Value::Boolean(b) => b.to_string(),
+    }
+}
+
+fn escape(value: &str, special: &[char]) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        if special.contains(&c) {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::{serve, Response};
+    use crate::sample_usage;
+
+    #[test]
+    fn test_line_protocol() {
+        let point = Point::new("dsmr")
+            .add_tag("reading", Value::String("low tariff".to_string()))
+            .add_tag("energy_type", Value::String("electricity".to_string()))
+            .add_field("value", Value::Float(2134.177))
+            .add_field("note", Value::String("a \"b\"".to_string()))
+            .add_timestamp(1608509313);
+        assert_eq!(
+            line_protocol(&Points::new(point)),
+            "dsmr,energy_type=electricity,reading=low\\ tariff note=\"a \\\"b\\\"\",value=2134.177 1608509313\n"
+        );
+    }
+
+    #[test]
+    fn test_schema() {
+        let data = sample_usage();
+        let schema: InfluxSchema = serde_json::from_str(
+            r#"{
+                "measurement": "energy",
+                "unit_tag": "",
+                "tags": {"site": "home"},
+                "readings": {
+                    "power_receiving": {"measurement": "power", "field": "import"},
+                    "voltage": {"skip": true},
+                    "gas_reading": {"name": "gas", "tags": {"building": "b"}}
+                }
+            }"#,
+        )
+        .unwrap();
+        schema.validate().unwrap();
+        let points =
+            usage_to_points(&data, Some("house"), TimestampSource::Meter, &schema).unwrap();
+        let lines = line_protocol(&points);
+        let lines: Vec<&str> = lines.lines().collect();
+        assert_eq!(lines.len(), 8);
+        assert_eq!(
+            lines[0],
+            "energy,energy_type=electricity,meter=house,reading=low_tariff,site=home value=2134.177 1608509313"
+        );
+        assert!(lines.contains(
+            &"power,energy_type=electricity,meter=house,reading=receiving,site=home import=0.229 1608509313"
+        ));
+        assert!(lines.contains(
+            &"energy,building=b,energy_type=gas,meter=house,reading=gas,site=home value=3799.479 1608509111"
+        ));
+
+        let schema: InfluxSchema = serde_json::from_str(r#"{"readings": {"power": {}}}"#).unwrap();
+        assert!(schema.validate().is_err());
+        assert!(serde_json::from_str::<InfluxSchema>(r#"{"measurment": "energy"}"#).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_write_v2() {
+        let (address, requests) = serve(vec![
+            Response::ok("application/json", r#"{"buckets": [{"name": "energy"}]}"#),
+            Response::ok("application/json", r#"{"buckets": []}"#),
+            Response::status(401),
+            Response::status(204),
+            Response::status(401),
+        ]);
+        let influx_db = InfluxDb::V2(InfluxDbV2::new(
+            Url::parse(&format!("http://{}", address)).unwrap(),
+            "home",
+            "energy",
+            "secret",
+        ));
+        let points = Points::new(Point::new("dsmr").add_field("value", Value::Float(0.229)));
+        let client = match &influx_db {
+            InfluxDb::V2(client) => client,
+            _ => unreachable!(),
+        };
+        assert!(client.check_bucket().await.unwrap());
+        let request = requests.recv().unwrap();
+        assert!(request.starts_with("GET /api/v2/buckets?org=home&name=energy "));
+        assert!(request.contains("authorization: Token secret\r\n"));
+        // Not visible to a token that may only write
+        assert!(!client.check_bucket().await.unwrap());
+        requests.recv().unwrap();
+        assert!(client.check_bucket().await.is_err());
+        requests.recv().unwrap();
+
+        influx_db
+            .write_points(points.clone(), Precision::Seconds)
+            .await
+            .unwrap();
+        let request = requests.recv().unwrap();
+        assert!(request.starts_with("POST /api/v2/write?org=home&bucket=energy&precision=s "));
+        assert!(request.contains("authorization: Token secret\r\n"));
+        assert!(request.ends_with("\r\n\r\ndsmr value=0.229\n"));
+
+        assert!(influx_db
+            .write_points(points, Precision::Seconds)
+            .await
+            .is_err());
+    }
+}
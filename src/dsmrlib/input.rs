@@ -0,0 +1,298 @@
+use crate::homewizard::HttpLines;
+use crate::replay::ReplayLines;
+use crate::RawTelegram;
+use chrono::Utc;
+use log::{debug, error, info};
+use reqwest::Url;
+use std::fmt;
+use std::io;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, BufReader};
+use tokio::net::TcpStream;
+use tokio::time::{sleep, timeout};
+use tokio_serial::{SerialPortBuilderExt, SerialStream};
+
+const BAUD_RATE: u32 = 115_200;
+const TCP_READ_TIMEOUT: u64 = 30;
+const RECONNECT_DELAY: u64 = 1;
+const MAX_RECONNECT_DELAY: u64 = 60;
+const POLL_INTERVAL: u64 = 1;
+const REPLAY_SPEED: f64 = 1.0;
+
+/// Where the raw P1 telegram stream is read from.
+///
+/// Parsed from a url-like string: `serial:///dev/ttyUSB0` or a bare device
+/// path for a local P1 cable, `tcp://host:port` for ser2net and WiFi dongles,
+/// `http://host/api/v1/telegram?interval=5` to poll a HomeWizard style API
+/// and `file:///path/capture.txt?speed=10` to replay a capture file. A replay
+/// speed of 0 reads the file as fast as possible.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Input {
+    Serial(String),
+    Tcp(String),
+    Http { url: Url, interval: Duration },
+    Replay { path: PathBuf, speed: f64 },
+}
+
+impl Input {
+    /// Opens the input for reading telegram lines.
+    pub async fn open(&self) -> io::Result<Lines> {
+        let source = match self {
+            Input::Serial(device) => {
+                let port = tokio_serial::new(device, BAUD_RATE).open_native_async()?;
+                info!("Receiving data on {} at {} baud:", device, BAUD_RATE);
+                Source::Serial(BufReader::new(port).lines())
+            }
+            Input::Tcp(address) => Source::Tcp(TcpLines::new(address)),
+            Input::Http { url, interval } => Source::Http(HttpLines::new(url, *interval)),
+            Input::Replay { path, speed } => Source::Replay(ReplayLines::open(path, *speed)?),
+        };
+        Ok(Lines { source })
+    }
+}
+
+enum Source {
+    Serial(tokio::io::Lines<BufReader<SerialStream>>),
+    Tcp(TcpLines),
+    Http(HttpLines),
+    Replay(ReplayLines),
+}
+
+/// Telegram lines read from an opened `Input`.
+pub struct Lines {
+    source: Source,
+}
+
+impl Lines {
+    /// Reads the next line, returns `None` when the input has ended, e.g. at
+    /// the end of a replayed capture file.
+    pub async fn next_line(&mut self) -> Option<String> {
+        let line = match &mut self.source {
+            Source::Serial(lines) => match lines.next_line().await {
+                Ok(line) => line,
+                Err(e) => {
+                    error!("Unable to read from serial port: {}", e);
+                    None
+                }
+            },
+            Source::Tcp(lines) => lines.next_line().await,
+            Source::Http(lines) => lines.next_line().await,
+            Source::Replay(lines) => lines.next_line().await,
+        };
+        debug!("lines mapping: {:?}", line);
+        line
+    }
+
+    /// Reads the next complete telegram, from its `/` header up to and
+    /// including the `!` CRC line.
+    pub async fn next_telegram(&mut self) -> Option<RawTelegram> {
+        let mut line = self.next_line().await?;
+        while !line.starts_with('/') {
+            line = self.next_line().await?;
+        }
+        let received = Utc::now();
+        let mut lines = vec![line];
+        while !lines[lines.len() - 1].starts_with('!') {
+            lines.push(self.next_line().await?);
+        }
+        Some(RawTelegram { received, lines })
+    }
+}
+
+impl FromStr for Input {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.starts_with('/') {
+            return Ok(Input::Serial(s.to_string()));
+        }
+        let url = Url::parse(s).map_err(|e| format!("Invalid input {}: {}", s, e))?;
+        match url.scheme() {
+            "serial" => Ok(Input::Serial(url.path().to_string())),
+            "tcp" => match (url.host_str(), url.port()) {
+                (Some(host), Some(port)) => Ok(Input::Tcp(format!("{}:{}", host, port))),
+                _ => Err(format!("Input {} needs a host and a port", s)),
+            },
+            "http" | "https" => {
+                let mut url = url;
+                let interval = match take_option(&mut url, "interval") {
+                    Some(i) => match i.parse() {
+                        Ok(0) => return Err("Interval must be at least 1 second".to_string()),
+                        Ok(interval) => interval,
+                        Err(e) => return Err(format!("Invalid interval {}: {}", i, e)),
+                    },
+                    None => POLL_INTERVAL,
+                };
+                Ok(Input::Http {
+                    url,
+                    interval: Duration::from_secs(interval),
+                })
+            }
+            "file" => {
+                let mut url = url;
+                let speed = match take_option(&mut url, "speed") {
+                    Some(s) => s
+                        .parse()
+                        .map_err(|e| format!("Invalid speed {}: {}", s, e))?,
+                    None => REPLAY_SPEED,
+                };
+                match url.to_file_path() {
+                    Ok(path) => Ok(Input::Replay { path, speed }),
+                    Err(_) => Err(format!("Input {} is not a valid file path", s)),
+                }
+            }
+            scheme => Err(format!("Unsupported input type: {}", scheme)),
+        }
+    }
+}
+
+/// Removes an energise specific option from the query string of `url`.
+fn take_option(url: &mut Url, key: &str) -> Option<String> {
+    let mut value = None;
+    let query: Vec<(String, String)> = url
+        .query_pairs()
+        .filter(|(k, v)| {
+            if k == key {
+                value = Some(v.to_string());
+            }
+            k != key
+        })
+        .map(|(k, v)| (k.into_owned(), v.into_owned()))
+        .collect();
+    url.set_query(None);
+    if !query.is_empty() {
+        url.query_pairs_mut().extend_pairs(query);
+    }
+    value
+}
+
+impl fmt::Display for Input {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Input::Serial(device) => write!(f, "serial://{}", device),
+            Input::Tcp(address) => write!(f, "tcp://{}", address),
+            Input::Http { url, .. } => write!(f, "{}", url),
+            Input::Replay { path, .. } => write!(f, "file://{}", path.display()),
+        }
+    }
+}
+
+/// Lines read from a TCP telegram stream, reconnecting whenever the
+/// connection drops or stays silent for longer than `TCP_READ_TIMEOUT`.
+struct TcpLines {
+    address: String,
+    lines: Option<tokio::io::Lines<BufReader<TcpStream>>>,
+}
+
+impl TcpLines {
+    fn new(address: &str) -> Self {
+        TcpLines {
+            address: address.to_string(),
+            lines: None,
+        }
+    }
+
+    async fn connect(&self) -> tokio::io::Lines<BufReader<TcpStream>> {
+        let mut delay = RECONNECT_DELAY;
+        loop {
+            match TcpStream::connect(&self.address).await {
+                Ok(stream) => {
+                    info!("Receiving data on tcp://{}", self.address);
+                    return BufReader::new(stream).lines();
+                }
+                Err(e) => {
+                    error!(
+                        "Unable to connect to {}: {}, retrying in {}s",
+                        self.address, e, delay
+                    );
+                    sleep(Duration::from_secs(delay)).await;
+                    delay = (delay * 2).min(MAX_RECONNECT_DELAY);
+                }
+            }
+        }
+    }
+
+    async fn next_line(&mut self) -> Option<String> {
+        loop {
+            let lines = match self.lines.as_mut() {
+                Some(lines) => lines,
+                None => self.lines.insert(self.connect().await),
+            };
+            match timeout(Duration::from_secs(TCP_READ_TIMEOUT), lines.next_line()).await {
+                Ok(Ok(Some(line))) => return Some(line),
+                Ok(Ok(None)) => error!("Connection closed by {}", self.address),
+                Ok(Err(e)) => error!("Lost connection to {}: {}", self.address, e),
+                Err(_) => error!(
+                    "No data from {} for {}s, reconnecting",
+                    self.address, TCP_READ_TIMEOUT
+                ),
+            }
+            self.lines = None;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+    use std::net::TcpListener;
+    use std::thread;
+
+    #[test]
+    fn test_parse_input() {
+        assert_eq!(
+            "/dev/ttyUSB0".parse::<Input>(),
+            Ok(Input::Serial("/dev/ttyUSB0".to_string()))
+        );
+        assert_eq!(
+            "serial:///dev/ttyUSB1".parse::<Input>(),
+            Ok(Input::Serial("/dev/ttyUSB1".to_string()))
+        );
+        assert_eq!(
+            "tcp://192.168.1.20:8088".parse::<Input>(),
+            Ok(Input::Tcp("192.168.1.20:8088".to_string()))
+        );
+        assert_eq!(
+            "http://192.168.1.30/api/v1/telegram?interval=10".parse::<Input>(),
+            Ok(Input::Http {
+                url: Url::parse("http://192.168.1.30/api/v1/telegram").unwrap(),
+                interval: Duration::from_secs(10),
+            })
+        );
+        assert_eq!(
+            "file:///var/lib/energise/capture.txt?speed=0".parse::<Input>(),
+            Ok(Input::Replay {
+                path: PathBuf::from("/var/lib/energise/capture.txt"),
+                speed: 0.0,
+            })
+        );
+        assert!("tcp://192.168.1.20".parse::<Input>().is_err());
+        assert!("http://192.168.1.30/api/v1/data?interval=0"
+            .parse::<Input>()
+            .is_err());
+        assert!("ftp://192.168.1.20:21".parse::<Input>().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_tcp_reconnects() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let server = thread::spawn(move || {
+            for line in ["/ISK5\\2M550E-1012\r\n", "!5C6B\r\n"].iter() {
+                let (mut stream, _) = listener.accept().unwrap();
+                stream.write_all(line.as_bytes()).unwrap();
+            }
+        });
+
+        let mut lines = Input::Tcp(address).open().await.unwrap();
+        assert_eq!(
+            lines.next_line().await,
+            Some("/ISK5\\2M550E-1012".to_string())
+        );
+        assert_eq!(lines.next_line().await, Some("!5C6B".to_string()));
+        server.join().unwrap();
+    }
+}
@@ -0,0 +1,148 @@
+use crate::ndjson::Telegram;
+use crate::{Sink, SinkError, UsageData};
+use async_trait::async_trait;
+use axum::extract::ws::{Message, WebSocket};
+use log::{debug, warn};
+use serde::Deserialize;
+use serde_json::{Map, Value};
+use std::sync::Arc;
+use tokio::sync::broadcast::{self, error::RecvError};
+
+/// Telegrams a client may fall behind by before it skips ahead.
+const CAPACITY: usize = 16;
+
+/// Fields sent along with every subscribed subset.
+const ALWAYS: [&str; 3] = ["meter", "received", "electricity_timestamp"];
+
+/// Parsed telegrams broadcast to WebSocket clients. Clones share the channel,
+/// so one clone can be written to as a sink while another is served.
+///
+/// A client that can't keep up skips the telegrams it missed, it never holds
+/// up the meters.
+#[derive(Debug, Clone)]
+pub struct Live {
+    sender: broadcast::Sender<Arc<Value>>,
+}
+
+impl Default for Live {
+    fn default() -> Self {
+        let (sender, _) = broadcast::channel(CAPACITY);
+        Live { sender }
+    }
+}
+
+/// What a client receives, every field of every meter by default.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+pub(crate) struct Subscription {
+    pub meter: Option<String>,
+    pub fields: Option<Vec<String>>,
+}
+
+impl Subscription {
+    fn filter(&self, telegram: &Value) -> Option<Value> {
+        if let Some(meter) = &self.meter {
+            if telegram["meter"].as_str() != Some(meter) {
+                return None;
+            }
+        }
+        let fields = match &self.fields {
+            Some(fields) => fields,
+            None => return Some(telegram.clone()),
+        };
+        let telegram = telegram.as_object()?;
+        let subset: Map<String, Value> = telegram
+            .iter()
+            .filter(|(key, _)| ALWAYS.contains(&key.as_str()) || fields.contains(key))
+            .map(|(key, value)| (key.clone(), value.clone()))
+            .collect();
+        Some(Value::Object(subset))
+    }
+}
+
+impl Live {
+    /// Number of connected clients.
+    pub fn clients(&self) -> usize {
+        self.sender.receiver_count()
+    }
+
+    /// Streams telegrams to a connected client until it disconnects. The
+    /// client can change its subscription by sending it as JSON, e.g.
+    /// `{"fields": ["power_receiving"]}`.
+    pub(crate) async fn stream(&self, mut socket: WebSocket, mut subscription: Subscription) {
+        let mut telegrams = self.sender.subscribe();
+        loop {
+            tokio::select! {
+                telegram = telegrams.recv() => match telegram {
+                    Ok(telegram) => {
+                        if let Some(message) = subscription.filter(&telegram) {
+                            if socket.send(Message::Text(message.to_string())).await.is_err() {
+                                return;
+                            }
+                        }
+                    }
+                    Err(RecvError::Lagged(skipped)) => {
+                        debug!("WebSocket client fell behind, skipped {} telegrams", skipped)
+                    }
+                    Err(RecvError::Closed) => return,
+                },
+                message = socket.recv() => match message {
+                    Some(Ok(Message::Text(text))) => match serde_json::from_str(&text) {
+                        Ok(s) => subscription = s,
+                        Err(e) => warn!("Invalid WebSocket subscription: {}", e),
+                    },
+                    Some(Ok(_)) => {}
+                    Some(Err(_)) | None => return,
+                },
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl Sink for Live {
+    fn name(&self) -> String {
+        "WebSocket".to_string()
+    }
+
+    async fn write(&mut self, meter: Option<&str>, data: &UsageData) -> Result<(), SinkError> {
+        if self.sender.receiver_count() > 0 {
+            let telegram = serde_json::to_value(Telegram { meter, data })?;
+            // Only fails when the last client just disconnected
+            self.sender.send(Arc::new(telegram)).ok();
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_subscription_filter() {
+        let telegram = json!({
+            "meter": "house",
+            "received": "2020-12-21T00:08:34Z",
+            "electricity_timestamp": "2020-12-21T01:08:33+01:00",
+            "power_receiving": {"value": 0.229, "unit": "kW"},
+            "voltage": {"value": 236.7, "unit": "V"},
+        });
+        assert_eq!(
+            Subscription::default().filter(&telegram),
+            Some(telegram.clone())
+        );
+        let subscription = Subscription {
+            meter: Some("house".to_string()),
+            fields: Some(vec!["voltage".to_string()]),
+        };
+        let mut expected = telegram.clone();
+        expected.as_object_mut().unwrap().remove("power_receiving");
+        assert_eq!(subscription.filter(&telegram), Some(expected));
+        let subscription = Subscription {
+            meter: Some("flat".to_string()),
+            fields: None,
+        };
+        assert_eq!(subscription.filter(&telegram), None);
+    }
+}
@@ -0,0 +1,424 @@
+use crate::spool::SpoolStatus;
+use crate::watchdog::{Liveness, WatchdogStatus};
+use crate::{Phase, Reading, Sink, SinkError, Spool, UsageData, Watchdog};
+use async_trait::async_trait;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Write;
+use std::sync::{Arc, Mutex};
+
+/// A metric family, `name` without the `_total` suffix of counters.
+struct Family {
+    name: &'static str,
+    kind: &'static str,
+    unit: &'static str,
+    help: &'static str,
+}
+
+const ELECTRICITY_RECEIVED: Family = Family {
+    name: "energise_electricity_received_kilowatt_hours",
+    kind: "counter",
+    unit: "kilowatt_hours",
+    help: "Electricity received from the grid",
+};
+const ELECTRICITY_RETURNED: Family = Family {
+    name: "energise_electricity_returned_kilowatt_hours",
+    kind: "counter",
+    unit: "kilowatt_hours",
+    help: "Electricity returned to the grid",
+};
+const POWER_RECEIVING: Family = Family {
+    name: "energise_power_receiving_kilowatts",
+    kind: "gauge",
+    unit: "kilowatts",
+    help: "Power currently received from the grid",
+};
+const POWER_RETURNING: Family = Family {
+    name: "energise_power_returning_kilowatts",
+    kind: "gauge",
+    unit: "kilowatts",
+    help: "Power currently returned to the grid",
+};
+const VOLTAGE: Family = Family {
+    name: "energise_voltage_volts",
+    kind: "gauge",
+    unit: "volts",
+    help: "Voltage per phase",
+};
+const CURRENT: Family = Family {
+    name: "energise_current_amperes",
+    kind: "gauge",
+    unit: "amperes",
+    help: "Current per phase",
+};
+const GAS: Family = Family {
+    name: "energise_gas_received_cubic_meters",
+    kind: "counter",
+    unit: "cubic_meters",
+    help: "Gas received",
+};
+
+/// Family and extra labels of every reading, voltage and current are
+/// exported per phase.
+const READINGS: [(&str, &Family, &str); 7] = [
+    (
+        "electricity_reading_low_tariff",
+        &ELECTRICITY_RECEIVED,
+        "tariff=\"low\"",
+    ),
+    (
+        "electricity_reading_normal_tariff",
+        &ELECTRICITY_RECEIVED,
+        "tariff=\"normal\"",
+    ),
+    (
+        "electricity_returned_reading_low_tariff",
+        &ELECTRICITY_RETURNED,
+        "tariff=\"low\"",
+    ),
+    (
+        "electricity_returned_reading_normal_tariff",
+        &ELECTRICITY_RETURNED,
+        "tariff=\"normal\"",
+    ),
+    ("power_receiving", &POWER_RECEIVING, ""),
+    ("power_returning", &POWER_RETURNING, ""),
+    ("gas_reading", &GAS, ""),
+];
+
+/// Value of a process metric, taken from the meter's watchdog.
+type StatusValue = fn(&WatchdogStatus) -> Option<f64>;
+
+/// Value of a per-phase metric.
+type PhaseValue = fn(&Phase) -> Option<f64>;
+
+/// Value of a spool metric.
+type SpoolValue = fn(&SpoolStatus) -> f64;
+
+#[derive(Debug, Default)]
+struct MeterMetrics {
+    watchdog: Option<Watchdog>,
+    spool: Option<Spool>,
+    readings: HashMap<&'static str, f64>,
+    phases: Vec<Phase>,
+}
+
+/// Latest readings and telegram counts of every meter, rendered in the
+/// OpenMetrics text format. Clones share their state, so one clone can be
+/// written to as a sink while another is served.
+#[derive(Debug, Clone, Default)]
+pub struct Metrics {
+    meters: Arc<Mutex<BTreeMap<String, MeterMetrics>>>,
+}
+
+impl Metrics {
+    /// Exports the telegram counts and liveness of a meter.
+    pub fn watch(&self, meter: Option<&str>, watchdog: Watchdog) {
+        let mut meters = self.meters.lock().unwrap();
+        meters
+            .entry(meter.unwrap_or("meter").to_string())
+            .or_default()
+            .watchdog = Some(watchdog);
+    }
+
+    /// Exports the state of the InfluxDB spool of a meter.
+    pub fn spool(&self, meter: Option<&str>, spool: Spool) {
+        let mut meters = self.meters.lock().unwrap();
+        meters
+            .entry(meter.unwrap_or("meter").to_string())
+            .or_default()
+            .spool = Some(spool);
+    }
+
+    /// Status of every watched meter.
+    pub fn statuses(&self) -> Vec<(String, WatchdogStatus)> {
+        let meters = self.meters.lock().unwrap();
+        meters
+            .iter()
+            .filter_map(|(meter, m)| Some((meter.clone(), m.watchdog.as_ref()?.status())))
+            .collect()
+    }
+
+    pub fn render(&self) -> String {
+        let meters = self.meters.lock().unwrap();
+        let mut text = String::new();
+        let mut families: Vec<&Family> = Vec::new();
+        for (_, family, _) in READINGS.iter() {
+            if !families.iter().any(|f| f.name == family.name) {
+                families.push(family);
+            }
+        }
+        for family in families {
+            header(&mut text, family);
+            for (meter, metrics) in meters.iter() {
+                for (reading, _, labels) in READINGS.iter().filter(|r| r.1.name == family.name) {
+                    if let Some(value) = metrics.readings.get(reading) {
+                        sample(&mut text, family, meter, labels, *value);
+                    }
+                }
+            }
+        }
+
+        let phase_values: [(&Family, PhaseValue); 2] =
+            [(&VOLTAGE, |p| p.voltage), (&CURRENT, |p| p.current)];
+        for (family, value) in phase_values.iter() {
+            header(&mut text, family);
+            for (meter, metrics) in meters.iter() {
+                for phase in &metrics.phases {
+                    if let Some(value) = value(phase) {
+                        let labels = format!("phase=\"l{}\"", phase.phase);
+                        sample(&mut text, family, meter, &labels, value);
+                    }
+                }
+            }
+        }
+
+        let watched: Vec<_> = meters
+            .iter()
+            .filter_map(|(meter, m)| Some((meter, m.watchdog.as_ref()?.status())))
+            .collect();
+        let process: [(Family, StatusValue); 6] = [
+            (
+                Family {
+                    name: "energise_telegrams_parsed",
+                    kind: "counter",
+                    unit: "",
+                    help: "Telegrams parsed successfully",
+                },
+                |s| Some(s.telegrams as f64),
+            ),
+            (
+                Family {
+                    name: "energise_crc_failures",
+                    kind: "counter",
+                    unit: "",
+                    help: "Telegrams with a CRC mismatch",
+                },
+                |s| Some(s.crc_failures as f64),
+            ),
+            (
+                Family {
+                    name: "energise_parse_errors",
+                    kind: "counter",
+                    unit: "",
+                    help: "Telegrams that could not be parsed",
+                },
+                |s| Some(s.parse_errors as f64),
+            ),
+            (
+                Family {
+                    name: "energise_input_restarts",
+                    kind: "counter",
+                    unit: "",
+                    help: "Times the input was reopened",
+                },
+                |s| Some(s.restarts as f64),
+            ),
+            (
+                Family {
+                    name: "energise_meter_up",
+                    kind: "gauge",
+                    unit: "",
+                    help: "Whether the meter is sending valid telegrams",
+                },
+                |s| {
+                    Some(if s.liveness == Liveness::Alive {
+                        1.0
+                    } else {
+                        0.0
+                    })
+                },
+            ),
+            (
+                Family {
+                    name: "energise_last_telegram_timestamp_seconds",
+                    kind: "gauge",
+                    unit: "seconds",
+                    help: "When the last valid telegram was received",
+                },
+                |s| {
+                    s.last_telegram
+                        .map(|t| t.timestamp_millis() as f64 / 1000.0)
+                },
+            ),
+        ];
+        for (family, value) in process.iter() {
+            header(&mut text, family);
+            for (meter, status) in &watched {
+                if let Some(value) = value(status) {
+                    sample(&mut text, family, meter, "", value);
+                }
+            }
+        }
+
+        let spooled: Vec<_> = meters
+            .iter()
+            .filter_map(|(meter, m)| Some((meter, m.spool.as_ref()?.status())))
+            .collect();
+        let spool: [(Family, SpoolValue); 4] = [
+            (
+                Family {
+                    name: "energise_spool_pending_points",
+                    kind: "gauge",
+                    unit: "",
+                    help: "Points spooled while InfluxDB was unavailable, waiting to be replayed",
+                },
+                |s| s.pending_points as f64,
+            ),
+            (
+                Family {
+                    name: "energise_spool_size_bytes",
+                    kind: "gauge",
+                    unit: "bytes",
+                    help: "Size of the spool on disk",
+                },
+                |s| s.size as f64,
+            ),
+            (
+                Family {
+                    name: "energise_spool_dropped_points",
+                    kind: "counter",
+                    unit: "",
+                    help: "Points lost because the spool was full or InfluxDB rejected them",
+                },
+                |s| s.dropped_points as f64,
+            ),
+            (
+                Family {
+                    name: "energise_spool_replayed_points",
+                    kind: "counter",
+                    unit: "",
+                    help: "Spooled points written to InfluxDB",
+                },
+                |s| s.replayed_points as f64,
+            ),
+        ];
+        if !spooled.is_empty() {
+            for (family, value) in spool.iter() {
+                header(&mut text, family);
+                for (meter, status) in &spooled {
+                    sample(&mut text, family, meter, "", value(status));
+                }
+            }
+        }
+        text.push_str("# EOF\n");
+        text
+    }
+}
+
+fn header(text: &mut String, family: &Family) {
+    writeln!(text, "# TYPE {} {}", family.name, family.kind).unwrap();
+    if !family.unit.is_empty() {
+        writeln!(text, "# UNIT {} {}", family.name, family.unit).unwrap();
+    }
+    writeln!(text, "# HELP {} {}", family.name, family.help).unwrap();
+}
+
+fn sample(text: &mut String, family: &Family, meter: &str, labels: &str, value: f64) {
+    let meter = meter
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n");
+    let suffix = if family.kind == "counter" {
+        "_total"
+    } else {
+        ""
+    };
+    let separator = if labels.is_empty() { "" } else { "," };
+    writeln!(
+        text,
+        "{}{}{{meter=\"{}\"{}{}}} {}",
+        family.name, suffix, meter, separator, labels, value
+    )
+    .unwrap();
+}
+
+#[async_trait]
+impl Sink for Metrics {
+    fn name(&self) -> String {
+        "metrics".to_string()
+    }
+
+    async fn write(&mut self, meter: Option<&str>, data: &UsageData) -> Result<(), SinkError> {
+        let mut meters = self.meters.lock().unwrap();
+        let metrics = meters
+            .entry(meter.unwrap_or("meter").to_string())
+            .or_default();
+        for (_, name, reading) in data.readings().iter() {
+            if let Reading::Measurement(m) = reading {
+                metrics.readings.insert(name, m.value);
+            }
+        }
+        metrics.phases = data.phases();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sample_usage;
+
+    #[tokio::test]
+    async fn test_render() {
+        let mut metrics = Metrics::default();
+        metrics.watch(Some("house"), Watchdog::default());
+        let data = sample_usage();
+        metrics.write(Some("house"), &data).await.unwrap();
+
+        let text = metrics.render();
+        assert!(text.contains(
+            "# TYPE energise_electricity_received_kilowatt_hours counter\n\
+             # UNIT energise_electricity_received_kilowatt_hours kilowatt_hours\n\
+             # HELP energise_electricity_received_kilowatt_hours Electricity received from the grid\n\
+             energise_electricity_received_kilowatt_hours_total{meter=\"house\",tariff=\"low\"} 2134.177\n\
+             energise_electricity_received_kilowatt_hours_total{meter=\"house\",tariff=\"normal\"} 3448.211\n"
+        ));
+        assert!(text.contains("energise_power_receiving_kilowatts{meter=\"house\"} 0.229\n"));
+        assert!(text.contains("energise_voltage_volts{meter=\"house\",phase=\"l1\"} 236.7\n"));
+        assert!(!text.contains("phase=\"l2\""));
+        assert!(text.contains("energise_telegrams_parsed_total{meter=\"house\"} 0\n"));
+        assert!(text.ends_with("# EOF\n"));
+
+        let mut data = data;
+        data.lines = vec![
+            "1-0:32.7.0(236.7*V)".to_string(),
+            "1-0:52.7.0(231.2*V)".to_string(),
+            "1-0:72.7.0(229.8*V)".to_string(),
+            "1-0:31.7.0(001*A)".to_string(),
+            "1-0:51.7.0(004*A)".to_string(),
+            "1-0:71.7.0(012*A)".to_string(),
+        ];
+        metrics.write(Some("flat"), &data).await.unwrap();
+        let text = metrics.render();
+        assert!(text.contains(
+            "energise_voltage_volts{meter=\"flat\",phase=\"l1\"} 236.7\n\
+             energise_voltage_volts{meter=\"flat\",phase=\"l2\"} 231.2\n\
+             energise_voltage_volts{meter=\"flat\",phase=\"l3\"} 229.8\n"
+        ));
+        assert!(text.contains("energise_current_amperes{meter=\"flat\",phase=\"l3\"} 12\n"));
+    }
+
+    #[tokio::test]
+    async fn test_spool_metrics() {
+        let directory = std::env::temp_dir().join("energise_test_spool_metrics");
+        std::fs::remove_dir_all(&directory).ok();
+        std::fs::create_dir_all(&directory).unwrap();
+        let segment = "{\"measurement\":\"dsmr\"}\n";
+        std::fs::write(directory.join("spool-0.ndjson"), segment.repeat(2)).unwrap();
+        let spool = Spool::new(directory.clone(), 1024 * 1024);
+        spool.open().await.unwrap();
+
+        let metrics = Metrics::default();
+        assert!(!metrics.render().contains("energise_spool"));
+        metrics.spool(Some("house"), spool);
+        let text = metrics.render();
+        assert!(text.contains("energise_spool_pending_points{meter=\"house\"} 2\n"));
+        assert!(text.contains(&format!(
+            "energise_spool_size_bytes{{meter=\"house\"}} {}\n",
+            segment.len() * 2
+        )));
+        assert!(text.contains("energise_spool_dropped_points_total{meter=\"house\"} 0\n"));
+        assert!(text.contains("energise_spool_replayed_points_total{meter=\"house\"} 0\n"));
+        std::fs::remove_dir_all(&directory).ok();
+    }
+}
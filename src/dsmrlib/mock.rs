@@ -0,0 +1,73 @@
+//! A minimal HTTP server for testing against, answering each connection with
+//! the next canned response and passing the received request back.
+
+use std::io::prelude::*;
+use std::io::BufReader;
+use std::net::TcpListener;
+use std::sync::mpsc::{self, Receiver};
+use std::thread;
+
+pub(crate) struct Response {
+    pub status: u16,
+    pub content_type: &'static str,
+    pub body: String,
+}
+
+impl Response {
+    pub(crate) fn ok(content_type: &'static str, body: &str) -> Self {
+        Response {
+            status: 200,
+            content_type,
+            body: body.to_string(),
+        }
+    }
+
+    pub(crate) fn status(status: u16) -> Self {
+        Response {
+            status,
+            content_type: "text/plain",
+            body: String::new(),
+        }
+    }
+}
+
+/// Serves `responses` in order, returns the server address and the requests
+/// as received, headers and body included.
+pub(crate) fn serve(responses: Vec<Response>) -> (String, Receiver<String>) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let address = listener.local_addr().unwrap().to_string();
+    let (sender, requests) = mpsc::channel();
+    thread::spawn(move || {
+        for response in responses {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            let mut request = String::new();
+            let mut content_length = 0;
+            loop {
+                let mut line = String::new();
+                if reader.read_line(&mut line).unwrap() == 0 || line == "\r\n" {
+                    break;
+                }
+                if let Some(length) = line.to_lowercase().strip_prefix("content-length:") {
+                    content_length = length.trim().parse().unwrap();
+                }
+                request.push_str(&line);
+            }
+            let mut body = vec![0; content_length];
+            reader.read_exact(&mut body).unwrap();
+            request.push_str("\r\n");
+            request.push_str(&String::from_utf8_lossy(&body));
+            sender.send(request).ok();
+            write!(
+                stream,
+                "HTTP/1.1 {} Mock\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                response.status,
+                response.content_type,
+                response.body.len(),
+                response.body
+            )
+            .unwrap();
+        }
+    });
+    (address, requests)
+}
@@ -0,0 +1,269 @@
+use crate::homeassistant::discovery_configs;
+use crate::{Reading, Sink, SinkError, UsageData};
+use async_trait::async_trait;
+use log::{info, warn};
+use reqwest::Url;
+use rumqttc::{AsyncClient, Event, MqttOptions, Packet, QoS, Transport};
+use std::error::Error;
+use std::fs;
+use std::path::PathBuf;
+use std::time::Duration;
+use tokio::time::sleep;
+
+/// Requests buffered by the MQTT client before publishing waits.
+const CLIENT_CAPACITY: usize = 64;
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+
+/// Publishes readings to an MQTT broker, each reading to its own topic such as
+/// `energise/<meter>/electricity/power_receiving`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Mqtt {
+    /// Broker as `mqtt://[user:password@]host[:port]`, or `mqtts://` for TLS.
+    pub url: Url,
+    pub client_id: String,
+    /// First level of the topic tree.
+    pub prefix: String,
+    pub qos: u8,
+    pub retain: bool,
+    /// Also publish the whole telegram as JSON to `<prefix>/<meter>/telegram`.
+    pub json: bool,
+    /// Also publish the raw telegram to `<prefix>/<meter>/raw`.
+    pub raw: bool,
+    /// Discovery prefix to publish Home Assistant discovery configs under,
+    /// usually `homeassistant`.
+    pub discovery: Option<String>,
+    /// CA certificate to verify the broker with instead of the system roots.
+    pub ca_file: Option<PathBuf>,
+    /// Client certificate and key, both PEM, for brokers requiring client
+    /// authentication. Needs `ca_file`.
+    pub client_auth: Option<(PathBuf, PathBuf)>,
+}
+
+impl Mqtt {
+    pub fn new(url: Url) -> Self {
+        Mqtt {
+            url,
+            client_id: "energise".to_string(),
+            prefix: "energise".to_string(),
+            qos: 0,
+            retain: false,
+            json: false,
+            raw: false,
+            discovery: None,
+            ca_file: None,
+            client_auth: None,
+        }
+    }
+
+    /// Connects to the broker, reconnecting in the background whenever the
+    /// connection drops. Clones of the sink share the connection.
+    pub fn connect(&self) -> Result<MqttSink, Box<dyn Error>> {
+        let host = self.url.host_str().ok_or("MQTT URL has no host")?;
+        let tls = match self.url.scheme() {
+            "mqtt" => false,
+            "mqtts" => true,
+            scheme => return Err(format!("Unsupported MQTT scheme: {}", scheme).into()),
+        };
+        let port = self.url.port().unwrap_or(if tls { 8883 } else { 1883 });
+        let mut options = MqttOptions::new(&self.client_id, host, port);
+        options.set_keep_alive(Duration::from_secs(30));
+        if !self.url.username().is_empty() {
+            options.set_credentials(self.url.username(), self.url.password().unwrap_or(""));
+        }
+        if tls {
+            let transport = match (&self.ca_file, &self.client_auth) {
+                (Some(ca), client_auth) => {
+                    let client_auth = match client_auth {
+                        Some((cert, key)) => Some((fs::read(cert)?, fs::read(key)?)),
+                        None => None,
+                    };
+                    Transport::tls(fs::read(ca)?, client_auth, None)
+                }
+                (None, Some(_)) => {
+                    return Err("MQTT client certificates need a CA file".into());
+                }
+                (None, None) => Transport::tls_with_default_config(),
+            };
+            options.set_transport(transport);
+        }
+        let qos = match self.qos {
+            0 => QoS::AtMostOnce,
+            1 => QoS::AtLeastOnce,
+            2 => QoS::ExactlyOnce,
+            qos => return Err(format!("Invalid MQTT QoS: {}", qos).into()),
+        };
+
+        let (client, mut eventloop) = AsyncClient::new(options, CLIENT_CAPACITY);
+        let broker = format!("{}:{}", host, port);
+        tokio::spawn(async move {
+            loop {
+                match eventloop.poll().await {
+                    Ok(Event::Incoming(Packet::ConnAck(_))) => {
+                        info!("Connected to MQTT broker {}", broker)
+                    }
+                    Ok(_) => {}
+                    Err(e) => {
+                        warn!(
+                            "MQTT connection to {} failed: {}, reconnecting in {:?}",
+                            broker, e, RECONNECT_DELAY
+                        );
+                        sleep(RECONNECT_DELAY).await;
+                    }
+                }
+            }
+        });
+        Ok(MqttSink {
+            client,
+            prefix: self.prefix.trim_end_matches('/').to_string(),
+            qos,
+            retain: self.retain,
+            json: self.json,
+            raw: self.raw,
+            discovery: self.discovery.clone(),
+            announced: false,
+        })
+    }
+}
+
+#[derive(Clone)]
+pub struct MqttSink {
+    client: AsyncClient,
+    prefix: String,
+    qos: QoS,
+    retain: bool,
+    json: bool,
+    raw: bool,
+    discovery: Option<String>,
+    /// Whether the discovery configs of the meter have been published.
+    announced: bool,
+}
+
+impl MqttSink {
+    async fn publish(&self, topic: String, payload: Vec<u8>) -> Result<(), SinkError> {
+        Ok(self
+            .client
+            .publish(topic, self.qos, self.retain, payload)
+            .await?)
+    }
+}
+
+#[async_trait]
+impl Sink for MqttSink {
+    fn name(&self) -> String {
+        "MQTT".to_string()
+    }
+
+    async fn write(&mut self, meter: Option<&str>, data: &UsageData) -> Result<(), SinkError> {
+        let meter = meter.unwrap_or("meter");
+        let base = format!("{}/{}", self.prefix, meter);
+        if let (Some(discovery), false) = (&self.discovery, self.announced) {
+            // Retained, so Home Assistant finds the sensors after it restarts
+            for (topic, config) in discovery_configs(discovery, &base, meter, data) {
+                self.client
+                    .publish(topic, self.qos, true, serde_json::to_vec(&config)?)
+                    .await?;
+            }
+            self.announced = true;
+        }
+        for (energy_type, name, reading) in data.readings().iter() {
+            if let Reading::Measurement(m) = reading {
+                let topic = format!("{}/{}/{}", base, energy_type, name);
+                self.publish(topic, m.value.to_string().into_bytes())
+                    .await?;
+            }
+        }
+        if self.json {
+            self.publish(format!("{}/telegram", base), serde_json::to_vec(data)?)
+                .await?;
+        }
+        if self.raw && !data.lines.is_empty() {
+            let mut telegram = data.lines.join("\r\n");
+            telegram.push_str("\r\n");
+            self.publish(format!("{}/raw", base), telegram.into_bytes())
+                .await?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{deserialise_p1_message, sample_telegram};
+    use std::io::prelude::*;
+    use std::net::{TcpListener, TcpStream};
+    use std::sync::mpsc::{self, Receiver};
+    use std::thread;
+
+    /// Reads one MQTT packet, returns its first header byte and its body.
+    fn read_packet(stream: &mut TcpStream) -> Option<(u8, Vec<u8>)> {
+        let mut header = [0; 1];
+        stream.read_exact(&mut header).ok()?;
+        let (mut length, mut shift) = (0, 0);
+        loop {
+            let mut byte = [0; 1];
+            stream.read_exact(&mut byte).ok()?;
+            length += ((byte[0] & 0x7f) as usize) << shift;
+            shift += 7;
+            if byte[0] & 0x80 == 0 {
+                break;
+            }
+        }
+        let mut body = vec![0; length];
+        stream.read_exact(&mut body).ok()?;
+        Some((header[0], body))
+    }
+
+    /// A broker accepting one client, passing on topic, payload and retain
+    /// flag of every QoS 0 publish.
+    fn broker() -> (u16, Receiver<(String, String, bool)>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let (sender, published) = mpsc::channel();
+        thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            while let Some((header, body)) = read_packet(&mut stream) {
+                match header >> 4 {
+                    1 => stream.write_all(&[0x20, 0x02, 0x00, 0x00]).unwrap(),
+                    3 => {
+                        let length = u16::from_be_bytes([body[0], body[1]]) as usize;
+                        let topic = String::from_utf8_lossy(&body[2..2 + length]).to_string();
+                        let payload = String::from_utf8_lossy(&body[2 + length..]).to_string();
+                        sender.send((topic, payload, header & 1 == 1)).ok();
+                    }
+                    12 => stream.write_all(&[0xd0, 0x00]).unwrap(),
+                    _ => {}
+                }
+            }
+        });
+        (port, published)
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_publish() {
+        let (port, published) = broker();
+        let mut mqtt = Mqtt::new(Url::parse(&format!("mqtt://127.0.0.1:{}", port)).unwrap());
+        mqtt.retain = true;
+        mqtt.raw = true;
+        let mut sink = mqtt.connect().unwrap();
+        let lines = sample_telegram();
+        let mut data = deserialise_p1_message(&lines).unwrap();
+        data.lines = lines;
+        sink.write(Some("house"), &data).await.unwrap();
+
+        let published: Vec<_> = published.iter().take(10).collect();
+        assert!(published.contains(&(
+            "energise/house/electricity/power_receiving".to_string(),
+            "0.229".to_string(),
+            true
+        )));
+        assert!(published.contains(&(
+            "energise/house/gas/gas_reading".to_string(),
+            "3799.479".to_string(),
+            true
+        )));
+        let (topic, raw, _) = &published[9];
+        assert_eq!(topic, "energise/house/raw");
+        assert!(raw.starts_with("/ISK5\\2M550E-1012\r\n") && raw.ends_with("!5C6B\r\n"));
+    }
+}
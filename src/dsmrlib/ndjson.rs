@@ -0,0 +1,79 @@
+use crate::{Sink, SinkError, UsageData};
+use async_trait::async_trait;
+use serde::Serialize;
+use tokio::io::{self, AsyncWrite, AsyncWriteExt};
+
+/// A telegram labelled with its meter, as written out as JSON.
+#[derive(Serialize)]
+pub(crate) struct Telegram<'a> {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub meter: Option<&'a str>,
+    #[serde(flatten)]
+    pub data: &'a UsageData,
+}
+
+/// Writes every telegram as one line of JSON, for piping into `jq`, vector or
+/// fluent-bit.
+pub struct Ndjson {
+    writer: Box<dyn AsyncWrite + Send + Unpin>,
+}
+
+impl Ndjson {
+    pub fn new(writer: impl AsyncWrite + Send + Unpin + 'static) -> Self {
+        Ndjson {
+            writer: Box::new(writer),
+        }
+    }
+
+    pub fn stdout() -> Self {
+        Ndjson::new(io::stdout())
+    }
+}
+
+#[async_trait]
+impl Sink for Ndjson {
+    fn name(&self) -> String {
+        "NDJSON".to_string()
+    }
+
+    async fn write(&mut self, meter: Option<&str>, data: &UsageData) -> Result<(), SinkError> {
+        let mut line = serde_json::to_vec(&Telegram { meter, data })?;
+        line.push(b'\n');
+        // One write per line so the lines of several meters never interleave
+        self.writer.write_all(&line).await?;
+        self.writer.flush().await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sample_usage;
+    use chrono::{TimeZone, Utc};
+    use serde_json::{json, Value};
+    use tokio::io::{AsyncBufReadExt, BufReader};
+
+    #[tokio::test]
+    async fn test_ndjson() {
+        let (writer, reader) = io::duplex(4096);
+        let mut sink = Ndjson::new(writer);
+        let mut data = sample_usage();
+        data.received = Some(Utc.with_ymd_and_hms(2020, 12, 21, 0, 8, 34).unwrap());
+        sink.write(Some("house"), &data).await.unwrap();
+        sink.write(None, &data).await.unwrap();
+
+        let mut lines = BufReader::new(reader).lines();
+        let line: Value = serde_json::from_str(&lines.next_line().await.unwrap().unwrap()).unwrap();
+        assert_eq!(line["meter"], "house");
+        assert_eq!(line["received"], "2020-12-21T00:08:34Z");
+        assert_eq!(line["electricity_timestamp"], "2020-12-21T01:08:33+01:00");
+        assert_eq!(
+            line["power_receiving"],
+            json!({"value": 0.229, "unit": "kW"})
+        );
+        assert_eq!(line["gas_timestamp"], "2020-12-21T01:05:11+01:00");
+        let line: Value = serde_json::from_str(&lines.next_line().await.unwrap().unwrap()).unwrap();
+        assert!(line.get("meter").is_none());
+    }
+}
@@ -0,0 +1,213 @@
+use log::{debug, warn};
+use std::collections::VecDeque;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use tokio::sync::Notify;
+
+/// What a full queue does with a new item.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DropPolicy {
+    /// Wait for the consumer to make room, slowing down the producer.
+    Block,
+    /// Discard the new item.
+    DropNewest,
+    /// Discard the oldest queued item to make room for the new one.
+    DropOldest,
+}
+
+impl FromStr for DropPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "block" => Ok(DropPolicy::Block),
+            "drop-newest" => Ok(DropPolicy::DropNewest),
+            "drop-oldest" => Ok(DropPolicy::DropOldest),
+            _ => Err(format!("Unsupported drop policy: {}", s)),
+        }
+    }
+}
+
+struct State<T> {
+    items: VecDeque<T>,
+    sender_closed: bool,
+    receiver_closed: bool,
+    overflowing: bool,
+}
+
+struct Shared<T> {
+    state: Mutex<State<T>>,
+    capacity: usize,
+    policy: DropPolicy,
+    dropped: AtomicU64,
+    items: Notify,
+    space: Notify,
+}
+
+/// Creates a bounded single producer, single consumer queue that handles a
+/// full queue according to `policy`.
+pub fn channel<T>(capacity: usize, policy: DropPolicy) -> (Sender<T>, Receiver<T>) {
+    let shared = Arc::new(Shared {
+        state: Mutex::new(State {
+            items: VecDeque::with_capacity(capacity),
+            sender_closed: false,
+            receiver_closed: false,
+            overflowing: false,
+        }),
+        capacity: capacity.max(1),
+        policy,
+        dropped: AtomicU64::new(0),
+        items: Notify::new(),
+        space: Notify::new(),
+    });
+    (
+        Sender {
+            shared: shared.clone(),
+        },
+        Receiver { shared },
+    )
+}
+
+pub struct Sender<T> {
+    shared: Arc<Shared<T>>,
+}
+
+impl<T> Sender<T> {
+    /// Queues `item`, only waiting for room with `DropPolicy::Block`.
+    /// Returns the item when the receiver is gone.
+    pub async fn send(&self, item: T) -> Result<(), T> {
+        let mut item = Some(item);
+        loop {
+            {
+                let mut state = self.shared.state.lock().unwrap();
+                if state.receiver_closed {
+                    return Err(item.take().unwrap());
+                }
+                if state.items.len() < self.shared.capacity {
+                    state.items.extend(item.take());
+                    state.overflowing = false;
+                    drop(state);
+                    self.shared.items.notify_one();
+                    return Ok(());
+                }
+                if self.shared.policy != DropPolicy::Block {
+                    if self.shared.policy == DropPolicy::DropOldest {
+                        state.items.pop_front();
+                        state.items.extend(item.take());
+                    }
+                    let dropped = self.shared.dropped.fetch_add(1, Ordering::Relaxed) + 1;
+                    if !state.overflowing {
+                        warn!("Queue full, dropping data ({} dropped so far)", dropped);
+                    } else {
+                        debug!("Queue full, {} dropped so far", dropped);
+                    }
+                    state.overflowing = true;
+                    return Ok(());
+                }
+            }
+            self.shared.space.notified().await;
+        }
+    }
+
+    /// Number of items discarded because the queue was full.
+    pub fn dropped(&self) -> u64 {
+        self.shared.dropped.load(Ordering::Relaxed)
+    }
+}
+
+impl<T> Drop for Sender<T> {
+    fn drop(&mut self) {
+        self.shared.state.lock().unwrap().sender_closed = true;
+        self.shared.items.notify_one();
+    }
+}
+
+pub struct Receiver<T> {
+    shared: Arc<Shared<T>>,
+}
+
+impl<T> Receiver<T> {
+    /// Waits for the next item, returns `None` once the sender is gone and
+    /// the queue is empty.
+    pub async fn recv(&mut self) -> Option<T> {
+        loop {
+            {
+                let mut state = self.shared.state.lock().unwrap();
+                if let Some(item) = state.items.pop_front() {
+                    drop(state);
+                    self.shared.space.notify_one();
+                    return Some(item);
+                }
+                if state.sender_closed {
+                    return None;
+                }
+            }
+            self.shared.items.notified().await;
+        }
+    }
+
+    /// Number of items waiting in the queue.
+    pub fn len(&self) -> usize {
+        self.shared.state.lock().unwrap().items.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Number of items discarded because the queue was full.
+    pub fn dropped(&self) -> u64 {
+        self.shared.dropped.load(Ordering::Relaxed)
+    }
+}
+
+impl<T> Drop for Receiver<T> {
+    fn drop(&mut self) {
+        self.shared.state.lock().unwrap().receiver_closed = true;
+        self.shared.space.notify_one();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+    use tokio::time::timeout;
+
+    #[tokio::test]
+    async fn test_drop_policies() {
+        let (sender, mut receiver) = channel(2, DropPolicy::DropNewest);
+        for i in 0..4 {
+            sender.send(i).await.unwrap();
+        }
+        drop(sender);
+        assert_eq!(receiver.recv().await, Some(0));
+        assert_eq!(receiver.recv().await, Some(1));
+        assert_eq!(receiver.recv().await, None);
+        assert_eq!(receiver.dropped(), 2);
+
+        let (sender, mut receiver) = channel(2, DropPolicy::DropOldest);
+        for i in 0..4 {
+            sender.send(i).await.unwrap();
+        }
+        drop(sender);
+        assert_eq!(receiver.recv().await, Some(2));
+        assert_eq!(receiver.recv().await, Some(3));
+        assert_eq!(receiver.recv().await, None);
+    }
+
+    #[tokio::test]
+    async fn test_block() {
+        let (sender, mut receiver) = channel(1, DropPolicy::Block);
+        sender.send(0).await.unwrap();
+        assert!(timeout(Duration::from_millis(50), sender.send(1))
+            .await
+            .is_err());
+        let producer = tokio::spawn(async move { sender.send(2).await });
+        assert_eq!(receiver.recv().await, Some(0));
+        assert_eq!(receiver.recv().await, Some(2));
+        assert!(producer.await.unwrap().is_ok());
+        drop(receiver);
+    }
+}
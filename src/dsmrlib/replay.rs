@@ -0,0 +1,147 @@
+use crate::archive::open_archive_file;
+use crate::parse_date;
+use chrono::{DateTime, FixedOffset};
+use log::{error, info, warn};
+use std::collections::VecDeque;
+use std::io;
+use std::io::prelude::*;
+use std::path::Path;
+use tokio::sync::mpsc::{self, Receiver, Sender};
+use tokio::task;
+use tokio::time::sleep;
+
+const READ_AHEAD: usize = 16;
+
+/// Telegram lines read back from a capture or archive file, paced by the
+/// meter timestamps in the telegrams divided by `speed`. A speed of 0
+/// disables pacing so an archive can be backfilled as fast as possible.
+pub(crate) struct ReplayLines {
+    telegrams: Receiver<Vec<String>>,
+    speed: f64,
+    previous: Option<DateTime<FixedOffset>>,
+    telegram: VecDeque<String>,
+}
+
+impl ReplayLines {
+    pub(crate) fn open(path: &Path, speed: f64) -> io::Result<Self> {
+        let reader = open_archive_file(path)?;
+        info!("Replaying {} at speed {}", path.display(), speed);
+        let (sender, telegrams) = mpsc::channel(READ_AHEAD);
+        task::spawn_blocking(move || read_telegrams(reader, sender));
+        Ok(ReplayLines {
+            telegrams,
+            speed,
+            previous: None,
+            telegram: VecDeque::new(),
+        })
+    }
+
+    async fn pace(&mut self) {
+        let line = self.telegram.iter().find(|l| l.starts_with("0-0:1.0.0("));
+        let timestamp = line.and_then(|l| {
+            let date = l.strip_prefix("0-0:1.0.0(")?.trim_end().strip_suffix(')')?;
+            parse_date(date, "%y%m%d%H%M%S").ok()
+        });
+        if let (Some(line), None) = (line, timestamp) {
+            warn!("Not pacing telegram with invalid timestamp: {}", line);
+        }
+        if let (Some(previous), Some(current)) = (self.previous, timestamp) {
+            if self.speed > 0.0 {
+                if let Ok(elapsed) = (current - previous).to_std() {
+                    sleep(elapsed.div_f64(self.speed)).await;
+                }
+            }
+        }
+        if timestamp.is_some() {
+            self.previous = timestamp;
+        }
+    }
+
+    pub(crate) async fn next_line(&mut self) -> Option<String> {
+        if self.telegram.is_empty() {
+            self.telegram.extend(self.telegrams.recv().await?);
+            self.pace().await;
+        }
+        self.telegram.pop_front()
+    }
+}
+
+/// Reads the file on a blocking thread, one telegram up to and including its
+/// `!` line at a time.
+fn read_telegrams(reader: Box<dyn BufRead + Send>, sender: Sender<Vec<String>>) {
+    let mut telegram = Vec::new();
+    for line in reader.lines() {
+        match line {
+            Ok(line) => {
+                let end = line.starts_with('!');
+                telegram.push(line);
+                if end && sender.blocking_send(std::mem::take(&mut telegram)).is_err() {
+                    return;
+                }
+            }
+            Err(e) => {
+                error!("Unable to read capture file: {}", e);
+                break;
+            }
+        }
+    }
+    if !telegram.is_empty() {
+        sender.blocking_send(telegram).ok();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::fs;
+    use std::time::Instant;
+
+    async fn count_lines(mut lines: ReplayLines) -> usize {
+        let mut count = 0;
+        while lines.next_line().await.is_some() {
+            count += 1;
+        }
+        count
+    }
+
+    #[tokio::test]
+    async fn test_replay_truncated() {
+        let path = env::temp_dir().join("energise_test_replay_truncated.txt");
+        fs::write(
+            &path,
+            "/ISK5\\2M550E-1012\r\n0-0:1.0.0(201221010833W)\r\n!5C6B\r\n\
+             /ISK5\\2M550E-1012\r\n0-0:1.0.0(\r\n!\r\n\
+             /ISK5\\2M550E-1012\r\n0-0:1.0.0()\r\n!\r\n0-0:1.0.0(2012",
+        )
+        .unwrap();
+        assert_eq!(
+            count_lines(ReplayLines::open(&path, 0.0).unwrap()).await,
+            10
+        );
+        fs::remove_file(&path).ok();
+    }
+
+    #[tokio::test]
+    async fn test_replay_pacing() {
+        let path = env::temp_dir().join("energise_test_replay_pacing.txt");
+        fs::write(
+            &path,
+            "/ISK5\\2M550E-1012\r\n0-0:1.0.0(201221010833W)\r\n!5C6B\r\n\
+             /ISK5\\2M550E-1012\r\n0-0:1.0.0(201221010843W)\r\n!1234\r\n",
+        )
+        .unwrap();
+
+        let start = Instant::now();
+        assert_eq!(
+            count_lines(ReplayLines::open(&path, 50.0).unwrap()).await,
+            6
+        );
+        assert!(start.elapsed().as_millis() >= 200);
+
+        let start = Instant::now();
+        assert_eq!(count_lines(ReplayLines::open(&path, 0.0).unwrap()).await, 6);
+        assert!(start.elapsed().as_millis() < 200);
+        fs::remove_file(path).unwrap();
+    }
+}
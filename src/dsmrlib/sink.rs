@@ -0,0 +1,334 @@
+use crate::queue::{self, DropPolicy, Sender};
+use crate::UsageData;
+use async_trait::async_trait;
+use log::{error, warn};
+use std::error::Error;
+use std::fmt;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::task::JoinHandle;
+use tokio::time::{interval, sleep, MissedTickBehavior};
+
+pub type SinkError = Box<dyn Error + Send + Sync>;
+
+/// A destination for parsed telegrams.
+///
+/// Every sink runs in its own task behind its own queue, so a slow or
+/// unavailable sink only holds up itself. Failed writes are retried as set by
+/// the sink's `Output`.
+#[async_trait]
+pub trait Sink: Send {
+    /// Name of the sink in logs.
+    fn name(&self) -> String;
+
+    /// Writes one telegram of the meter labelled `meter`.
+    async fn write(&mut self, meter: Option<&str>, data: &UsageData) -> Result<(), SinkError>;
+
+    /// Called every `Output::flush_interval`, to write out what has been
+    /// buffered for too long while no telegrams arrive.
+    async fn flush(&mut self) -> Result<(), SinkError> {
+        Ok(())
+    }
+
+    /// Called once the input has ended, to write out anything buffered.
+    async fn close(&mut self) -> Result<(), SinkError> {
+        Ok(())
+    }
+}
+
+/// How often a failed write is attempted, with the delay doubling between
+/// attempts up to `max_delay`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Retry {
+    pub attempts: u32,
+    pub delay: Duration,
+    pub max_delay: Duration,
+}
+
+impl Default for Retry {
+    fn default() -> Self {
+        Retry {
+            attempts: 3,
+            delay: Duration::from_secs(1),
+            max_delay: Duration::from_secs(30),
+        }
+    }
+}
+
+/// A sink together with its queue and retry settings.
+pub struct Output {
+    pub sink: Box<dyn Sink>,
+    /// Number of telegrams buffered for the sink.
+    pub queue_size: usize,
+    /// What happens to new telegrams while the sink falls behind.
+    pub drop_policy: DropPolicy,
+    pub retry: Retry,
+    /// How often the sink is asked to flush.
+    pub flush_interval: Duration,
+}
+
+impl Output {
+    pub fn new(sink: impl Sink + 'static) -> Self {
+        Output {
+            sink: Box::new(sink),
+            queue_size: 64,
+            drop_policy: DropPolicy::DropOldest,
+            retry: Retry::default(),
+            flush_interval: Duration::from_secs(1),
+        }
+    }
+
+    /// Starts writing to the sink, the task ends once the returned sender is
+    /// dropped and the queue is drained.
+    pub(crate) fn spawn(self, meter: Option<String>) -> (Sender<Arc<UsageData>>, JoinHandle<()>) {
+        let (sender, mut receiver) =
+            queue::channel::<Arc<UsageData>>(self.queue_size, self.drop_policy);
+        let mut sink = self.sink;
+        let retry = self.retry;
+        let flush_interval = self.flush_interval;
+        let handle = tokio::spawn(async move {
+            let mut ticks = interval(flush_interval);
+            ticks.set_missed_tick_behavior(MissedTickBehavior::Delay);
+            loop {
+                let data = tokio::select! {
+                    data = receiver.recv() => match data {
+                        Some(data) => data,
+                        None => break,
+                    },
+                    _ = ticks.tick() => {
+                        // Left buffered, so the next tick tries again
+                        if let Err(e) = sink.flush().await {
+                            warn!("Flushing {} failed: {}", sink.name(), e);
+                        }
+                        continue;
+                    }
+                };
+                let mut delay = retry.delay;
+                for attempt in 1.. {
+                    match sink.write(meter.as_deref(), &data).await {
+                        Ok(()) => break,
+                        Err(e) if attempt < retry.attempts => {
+                            warn!(
+                                "Writing to {} failed: {}, retrying in {:?}",
+                                sink.name(),
+                                e,
+                                delay
+                            );
+                            sleep(delay).await;
+                            delay = (delay * 2).min(retry.max_delay);
+                        }
+                        Err(e) => {
+                            error!(
+                                "Writing to {} failed {} times, dropping telegram: {}",
+                                sink.name(),
+                                attempt,
+                                e
+                            );
+                            break;
+                        }
+                    }
+                }
+            }
+            if let Err(e) = sink.close().await {
+                error!("Closing {} failed: {}", sink.name(), e);
+            }
+        });
+        (sender, handle)
+    }
+}
+
+impl fmt::Debug for Output {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Output")
+            .field("sink", &self.sink.name())
+            .field("queue_size", &self.queue_size)
+            .field("drop_policy", &self.drop_policy)
+            .field("retry", &self.retry)
+            .field("flush_interval", &self.flush_interval)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{sample_text, DsmrClient, Input, Watchdog};
+    use std::sync::Mutex;
+
+    /// Records what it is sent, failing the first `failures` writes.
+    struct TestSink {
+        failures: u32,
+        written: Arc<Mutex<Vec<Option<String>>>>,
+    }
+
+    #[async_trait]
+    impl Sink for TestSink {
+        fn name(&self) -> String {
+            "test".to_string()
+        }
+
+        async fn write(&mut self, meter: Option<&str>, _data: &UsageData) -> Result<(), SinkError> {
+            if self.failures > 0 {
+                self.failures -= 1;
+                return Err("unavailable".into());
+            }
+            self.written.lock().unwrap().push(meter.map(str::to_string));
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_fan_out() {
+        let path = std::env::temp_dir().join("energise_test_fan_out.txt");
+        let telegram = sample_text();
+        std::fs::write(&path, format!("{}{}", telegram, telegram)).unwrap();
+
+        let written = Arc::new(Mutex::new(Vec::new()));
+        let flaky = Arc::new(Mutex::new(Vec::new()));
+        let mut retrying = Output::new(TestSink {
+            failures: 2,
+            written: flaky.clone(),
+        });
+        retrying.drop_policy = DropPolicy::Block;
+        retrying.retry.delay = Duration::from_millis(1);
+        let mut giving_up = Output::new(TestSink {
+            failures: 1,
+            written: written.clone(),
+        });
+        giving_up.drop_policy = DropPolicy::Block;
+        giving_up.retry.attempts = 1;
+
+        DsmrClient {
+            meter: Some("house".to_string()),
+            input: Input::Replay {
+                path: path.clone(),
+                speed: 0.0,
+            },
+            archive: None,
+            queue_size: 1,
+            drop_policy: DropPolicy::Block,
+            watchdog: Watchdog::default(),
+            aggregate: None,
+            sinks: vec![retrying, giving_up],
+        }
+        .run()
+        .await;
+
+        assert_eq!(flaky.lock().unwrap().len(), 2);
+        assert_eq!(*written.lock().unwrap(), vec![Some("house".to_string())]);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    /// Records what the output calls.
+    struct EventSink(Arc<Mutex<Vec<&'static str>>>);
+
+    #[async_trait]
+    impl Sink for EventSink {
+        fn name(&self) -> String {
+            "events".to_string()
+        }
+
+        async fn write(
+            &mut self,
+            _meter: Option<&str>,
+            _data: &UsageData,
+        ) -> Result<(), SinkError> {
+            self.0.lock().unwrap().push("write");
+            Ok(())
+        }
+
+        async fn flush(&mut self) -> Result<(), SinkError> {
+            self.0.lock().unwrap().push("flush");
+            Ok(())
+        }
+
+        async fn close(&mut self) -> Result<(), SinkError> {
+            self.0.lock().unwrap().push("close");
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_flush_without_telegrams() {
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let mut output = Output::new(EventSink(events.clone()));
+        output.flush_interval = Duration::from_millis(10);
+        let (sender, handle) = output.spawn(None);
+        sleep(Duration::from_millis(100)).await;
+        drop(sender);
+        handle.await.unwrap();
+        let events = events.lock().unwrap();
+        assert!(events.iter().filter(|e| **e == "flush").count() >= 2);
+        assert_eq!(events.last(), Some(&"close"));
+    }
+
+    #[tokio::test]
+    async fn test_shutdown() {
+        // Replayed in real time, the second telegram is an hour away
+        let path = std::env::temp_dir().join("energise_test_shutdown.txt");
+        let telegram = sample_text();
+        let later = telegram.replace("201221010833W", "201221020833W");
+        std::fs::write(&path, telegram + &later).unwrap();
+
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let client = DsmrClient {
+            meter: None,
+            input: Input::Replay {
+                path: path.clone(),
+                speed: 1.0,
+            },
+            archive: None,
+            queue_size: 1,
+            drop_policy: DropPolicy::Block,
+            watchdog: Watchdog::default(),
+            aggregate: None,
+            sinks: vec![Output::new(EventSink(events.clone()))],
+        };
+        tokio::time::timeout(
+            Duration::from_secs(10),
+            client.run_until(sleep(Duration::from_millis(200))),
+        )
+        .await
+        .unwrap();
+
+        let events = events.lock().unwrap();
+        assert_eq!(events.iter().filter(|e| **e == "write").count(), 1);
+        assert_eq!(events.last(), Some(&"close"));
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_shutdown_aggregate() {
+        let path = std::env::temp_dir().join("energise_test_shutdown_aggregate.txt");
+        let telegram = sample_text();
+        let later = telegram.replace("201221010833W", "201221020833W");
+        std::fs::write(&path, telegram + &later).unwrap();
+
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let client = DsmrClient {
+            meter: None,
+            input: Input::Replay {
+                path: path.clone(),
+                speed: 1.0,
+            },
+            archive: None,
+            queue_size: 1,
+            drop_policy: DropPolicy::Block,
+            watchdog: Watchdog::default(),
+            aggregate: Some(Duration::from_secs(300)),
+            sinks: vec![Output::new(EventSink(events.clone()))],
+        };
+        tokio::time::timeout(
+            Duration::from_secs(10),
+            client.run_until(sleep(Duration::from_millis(200))),
+        )
+        .await
+        .unwrap();
+
+        // The interval still open is written rather than dropped
+        let events = events.lock().unwrap();
+        assert_eq!(events.iter().filter(|e| **e == "write").count(), 1);
+        assert_eq!(events.last(), Some(&"close"));
+        std::fs::remove_file(path).unwrap();
+    }
+}
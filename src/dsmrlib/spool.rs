@@ -0,0 +1,501 @@
+use crate::{InfluxDb, WriteError};
+use chrono::Utc;
+use influx_db_client::{Point, Points, Precision, Value};
+use log::{error, info, warn};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashSet, VecDeque};
+use std::io;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::fs::{self, OpenOptions};
+use tokio::io::AsyncWriteExt;
+use tokio::time::Instant;
+
+/// Largest spool segment, the spool is trimmed a whole segment at a time.
+const SEGMENT_SIZE: u64 = 1024 * 1024;
+/// Points per write while replaying.
+const BATCH_SIZE: usize = 1000;
+/// Time between attempts to replay the spool while InfluxDB is unavailable.
+const RETRY_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Write-ahead spool for points that could not be written to InfluxDB.
+///
+/// Writes that failed because InfluxDB was unavailable are appended to
+/// segment files in `directory`, together at most `max_size` bytes, the
+/// oldest segments are dropped when it fills up. Once InfluxDB accepts
+/// writes again the spool is replayed in order, in batches and without
+/// duplicate points, before any new points are written. Points InfluxDB
+/// rejects are dropped instead, they would never be written. The spool
+/// survives restarts.
+#[derive(Debug, Clone)]
+pub struct Spool {
+    pub directory: PathBuf,
+    pub max_size: u64,
+    status: Arc<Mutex<SpoolStatus>>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct SpoolStatus {
+    /// Points waiting to be replayed.
+    pub pending_points: u64,
+    /// Size of the spool on disk in bytes.
+    pub size: u64,
+    pub replayed_points: u64,
+    /// Points skipped during replay because they were spooled twice.
+    pub duplicate_points: u64,
+    /// Points lost because the spool was full or InfluxDB rejected them.
+    pub dropped_points: u64,
+}
+
+impl Spool {
+    pub fn new(directory: PathBuf, max_size: u64) -> Self {
+        Spool {
+            directory,
+            max_size,
+            status: Default::default(),
+        }
+    }
+
+    /// Status of the spool, shared by clones.
+    pub fn status(&self) -> SpoolStatus {
+        self.status.lock().unwrap().clone()
+    }
+
+    /// Opens the spool, picking up segments left by a previous run.
+    pub(crate) async fn open(&self) -> io::Result<SpoolWriter> {
+        fs::create_dir_all(&self.directory).await?;
+        let mut segments = Vec::new();
+        let mut entries = fs::read_dir(&self.directory).await?;
+        while let Some(entry) = entries.next_entry().await? {
+            let name = entry.file_name().to_string_lossy().to_string();
+            if let Some(sequence) = name
+                .strip_prefix("spool-")
+                .and_then(|n| n.strip_suffix(".ndjson"))
+                .and_then(|n| n.parse::<u64>().ok())
+            {
+                let text = fs::read_to_string(entry.path()).await?;
+                segments.push(Segment {
+                    sequence,
+                    path: entry.path(),
+                    size: text.len() as u64,
+                    points: text.lines().count() as u64,
+                });
+            }
+        }
+        segments.sort_by_key(|s| s.sequence);
+        {
+            let mut status = self.status.lock().unwrap();
+            status.pending_points = segments.iter().map(|s| s.points).sum();
+            status.size = segments.iter().map(|s| s.size).sum();
+            if status.pending_points > 0 {
+                info!(
+                    "Spool {} holds {} points to replay",
+                    self.directory.display(),
+                    status.pending_points
+                );
+            }
+        }
+        Ok(SpoolWriter {
+            next_sequence: segments.last().map_or(0, |s| s.sequence + 1),
+            segments: segments.into(),
+            appending: false,
+            offset: 0,
+            retry_at: Instant::now(),
+            spool: self.clone(),
+        })
+    }
+}
+
+struct Segment {
+    sequence: u64,
+    path: PathBuf,
+    size: u64,
+    points: u64,
+}
+
+/// A point as stored in the spool, one JSON object per line.
+#[derive(Serialize, Deserialize)]
+struct SpooledPoint {
+    measurement: String,
+    tags: BTreeMap<String, serde_json::Value>,
+    fields: BTreeMap<String, serde_json::Value>,
+    timestamp: i64,
+}
+
+impl SpooledPoint {
+    fn new(point: &Point, now: i64) -> Self {
+        let json = |values: &std::collections::HashMap<String, Value>| {
+            values
+                .iter()
+                .map(|(key, value)| {
+                    let value = match value {
+                        Value::String(s) => serde_json::Value::from(s.as_str()),
+                        Value::Integer(i) => serde_json::Value::from(*i),
+                        Value::Float(f) => serde_json::Value::from(*f),
+                        Value::Boolean(b) => serde_json::Value::from(*b),
+                    };
+                    (key.clone(), value)
+                })
+                .collect()
+        };
+        SpooledPoint {
+            measurement: point.measurement.clone(),
+            tags: json(&point.tags),
+            fields: json(&point.fields),
+            // Points without a timestamp would be stamped with the replay time
+            timestamp: point.timestamp.unwrap_or(now),
+        }
+    }
+
+    /// Series and time of the point, points with the same key overwrite
+    /// each other in InfluxDB.
+    fn key(&self) -> String {
+        serde_json::to_string(&(&self.measurement, &self.tags, self.timestamp)).unwrap_or_default()
+    }
+
+    fn to_point(&self) -> Point {
+        let mut point = Point::new(&self.measurement);
+        for (key, value) in &self.tags {
+            if let Some(value) = from_json(value) {
+                point = point.add_tag(key, value);
+            }
+        }
+        for (key, value) in &self.fields {
+            if let Some(value) = from_json(value) {
+                point = point.add_field(key, value);
+            }
+        }
+        point.add_timestamp(self.timestamp)
+    }
+}
+
+fn from_json(value: &serde_json::Value) -> Option<Value> {
+    match value {
+        serde_json::Value::String(s) => Some(Value::String(s.clone())),
+        serde_json::Value::Bool(b) => Some(Value::Boolean(*b)),
+        serde_json::Value::Number(n) => match n.as_i64() {
+            Some(i) => Some(Value::Integer(i)),
+            None => n.as_f64().map(Value::Float),
+        },
+        _ => None,
+    }
+}
+
+/// The opened spool, owned by the task writing a meter's points.
+pub(crate) struct SpoolWriter {
+    spool: Spool,
+    segments: VecDeque<Segment>,
+    next_sequence: u64,
+    /// Whether new points go to the last segment, it is closed once replay
+    /// starts reading it.
+    appending: bool,
+    /// Points of the first segment that have already been replayed.
+    offset: u64,
+    retry_at: Instant,
+}
+
+impl SpoolWriter {
+    pub(crate) fn is_empty(&self) -> bool {
+        self.segments.is_empty()
+    }
+
+    /// Writes `points`, or spools them while InfluxDB is unavailable or older
+    /// points are still waiting to be replayed.
+    pub(crate) async fn write(&mut self, influx_db: &InfluxDb, points: Points) {
+        if !self.is_empty() && Instant::now() >= self.retry_at {
+            self.replay(influx_db).await;
+        }
+        if self.is_empty() {
+            match influx_db
+                .write_points(points.clone(), Precision::Seconds)
+                .await
+            {
+                Ok(()) => return,
+                Err(WriteError::Rejected(e)) => return self.reject(points.point.len(), &e),
+                Err(WriteError::Unavailable(e)) => {
+                    warn!(
+                        "Unable to write to InfluxDB: {}, spooling to {}",
+                        e,
+                        self.spool.directory.display()
+                    );
+                    self.retry_at = Instant::now() + RETRY_INTERVAL;
+                }
+            }
+        }
+        if let Err(e) = self.push(&points).await {
+            error!("Unable to spool points, they are lost: {}", e);
+        }
+    }
+
+    /// Appends `points` to the spool, dropping the oldest segments when it
+    /// would grow past its maximum size.
+    pub(crate) async fn push(&mut self, points: &Points) -> io::Result<()> {
+        let now = Utc::now().timestamp();
+        let mut text = String::new();
+        for point in &points.point {
+            text.push_str(&serde_json::to_string(&SpooledPoint::new(point, now))?);
+            text.push('\n');
+        }
+        let size = text.len() as u64;
+        let count = points.point.len() as u64;
+        while !self.segments.is_empty() && self.spool.status().size + size > self.spool.max_size {
+            self.drop_oldest().await;
+        }
+        if size > self.spool.max_size {
+            self.spool.status.lock().unwrap().dropped_points += count;
+            return Ok(());
+        }
+
+        let segment_size = (self.spool.max_size / 16).clamp(1, SEGMENT_SIZE);
+        if !self.appending || self.segments.back().is_none_or(|s| s.size >= segment_size) {
+            let path = self
+                .spool
+                .directory
+                .join(format!("spool-{:010}.ndjson", self.next_sequence));
+            self.segments.push_back(Segment {
+                sequence: self.next_sequence,
+                path,
+                size: 0,
+                points: 0,
+            });
+            self.next_sequence += 1;
+            self.appending = true;
+        }
+        let segment = self.segments.back_mut().unwrap();
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&segment.path)
+            .await?;
+        file.write_all(text.as_bytes()).await?;
+        file.flush().await?;
+        segment.size += size;
+        segment.points += count;
+        let mut status = self.spool.status.lock().unwrap();
+        status.size += size;
+        status.pending_points += count;
+        Ok(())
+    }
+
+    /// Drops `count` points InfluxDB refused to store.
+    fn reject(&self, count: usize, error: &str) {
+        self.spool.status.lock().unwrap().dropped_points += count as u64;
+        error!(
+            "InfluxDB rejected {} points, dropping them: {}",
+            count, error
+        );
+    }
+
+    async fn drop_oldest(&mut self) {
+        let offset = self.offset;
+        if let Some(segment) = self.pop_segment().await {
+            let dropped = segment.points - offset;
+            self.spool.status.lock().unwrap().dropped_points += dropped;
+            warn!(
+                "Spool {} is full, dropped {} points",
+                self.spool.directory.display(),
+                dropped
+            );
+        }
+    }
+
+    async fn pop_segment(&mut self) -> Option<Segment> {
+        let segment = self.segments.pop_front()?;
+        if let Err(e) = fs::remove_file(&segment.path).await {
+            error!("Unable to remove {}: {}", segment.path.display(), e);
+        }
+        if self.segments.is_empty() {
+            self.appending = false;
+        }
+        let mut status = self.spool.status.lock().unwrap();
+        status.size -= segment.size;
+        status.pending_points -= segment.points - std::mem::take(&mut self.offset);
+        Some(segment)
+    }
+
+    /// Replays spooled points in order, oldest first, until the spool is
+    /// empty or InfluxDB is unavailable. Batches InfluxDB rejects are dropped.
+    pub(crate) async fn replay(&mut self, influx_db: &InfluxDb) {
+        // Points spooled again can be in several segments
+        let mut seen = HashSet::new();
+        while let Some(segment) = self.segments.front() {
+            if self.segments.len() == 1 {
+                self.appending = false;
+            }
+            let text = match fs::read_to_string(&segment.path).await {
+                Ok(text) => text,
+                Err(e) => {
+                    error!("Unable to read {}: {}", segment.path.display(), e);
+                    self.drop_oldest().await;
+                    continue;
+                }
+            };
+            let lines: Vec<&str> = text.lines().skip(self.offset as usize).collect();
+            for batch in lines.chunks(BATCH_SIZE) {
+                let mut points = Vec::new();
+                for line in batch {
+                    match serde_json::from_str::<SpooledPoint>(line) {
+                        Ok(point) if seen.insert(point.key()) => points.push(point.to_point()),
+                        Ok(_) => self.spool.status.lock().unwrap().duplicate_points += 1,
+                        Err(e) => error!("Skipping corrupt spooled point: {}", e),
+                    }
+                }
+                let mut written = points.len() as u64;
+                if !points.is_empty() {
+                    match influx_db
+                        .write_points(Points { point: points }, Precision::Seconds)
+                        .await
+                    {
+                        Ok(()) => {}
+                        Err(WriteError::Rejected(e)) => {
+                            self.reject(written as usize, &e);
+                            written = 0;
+                        }
+                        Err(WriteError::Unavailable(e)) => {
+                            warn!(
+                                "Unable to replay spool: {}, {} points pending",
+                                e,
+                                self.spool.status().pending_points
+                            );
+                            self.retry_at = Instant::now() + RETRY_INTERVAL;
+                            return;
+                        }
+                    }
+                }
+                self.offset += batch.len() as u64;
+                let mut status = self.spool.status.lock().unwrap();
+                status.pending_points -= batch.len() as u64;
+                status.replayed_points += written;
+            }
+            self.pop_segment().await;
+        }
+        info!(
+            "Spool {} replayed, {} points written in total",
+            self.spool.directory.display(),
+            self.spool.status().replayed_points
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::{serve, Response};
+    use crate::InfluxDbV2;
+    use reqwest::Url;
+
+    fn point(value: f64, timestamp: i64) -> Points {
+        Points::new(
+            Point::new("dsmr")
+                .add_tag("reading", Value::String("receiving".to_string()))
+                .add_field("value", Value::Float(value))
+                .add_timestamp(timestamp),
+        )
+    }
+
+    #[tokio::test]
+    async fn test_spool_replay() {
+        let directory = std::env::temp_dir().join("energise_test_spool_replay");
+        std::fs::remove_dir_all(&directory).ok();
+        let (address, requests) = serve(vec![
+            Response::status(503),
+            Response::status(204),
+            Response::status(204),
+        ]);
+        let influx_db = InfluxDb::V2(InfluxDbV2::new(
+            Url::parse(&format!("http://{}", address)).unwrap(),
+            "home",
+            "energy",
+            "secret",
+        ));
+        let spool = Spool::new(directory.clone(), 1024 * 1024);
+        let mut writer = spool.open().await.unwrap();
+
+        writer.write(&influx_db, point(0.1, 1)).await;
+        requests.recv().unwrap();
+        writer.push(&point(0.2, 2)).await.unwrap();
+        // Spooled again after a restart, in a segment of its own
+        let mut writer = spool.open().await.unwrap();
+        writer.push(&point(0.1, 1)).await.unwrap();
+        assert_eq!(writer.segments.len(), 2);
+        assert_eq!(spool.status().pending_points, 3);
+
+        // A restart picks the spool up again
+        let spool = Spool::new(directory.clone(), 1024 * 1024);
+        let mut writer = spool.open().await.unwrap();
+        assert_eq!(spool.status().pending_points, 3);
+        writer.replay(&influx_db).await;
+        assert!(requests.recv().unwrap().ends_with(
+            "\r\n\r\ndsmr,reading=receiving value=0.1 1\ndsmr,reading=receiving value=0.2 2\n"
+        ));
+        let status = spool.status();
+        assert_eq!(
+            (
+                status.pending_points,
+                status.size,
+                status.replayed_points,
+                status.duplicate_points
+            ),
+            (0, 0, 2, 1)
+        );
+        assert!(writer.is_empty());
+
+        writer.write(&influx_db, point(0.3, 3)).await;
+        assert!(requests.recv().unwrap().ends_with("value=0.3 3\n"));
+        std::fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_spool_rejected() {
+        let directory = std::env::temp_dir().join("energise_test_spool_rejected");
+        std::fs::remove_dir_all(&directory).ok();
+        let (address, requests) = serve(vec![
+            Response::status(400),
+            Response::status(503),
+            Response::status(422),
+            Response::status(204),
+        ]);
+        let influx_db = InfluxDb::V2(InfluxDbV2::new(
+            Url::parse(&format!("http://{}", address)).unwrap(),
+            "home",
+            "energy",
+            "secret",
+        ));
+        let spool = Spool::new(directory.clone(), 1024 * 1024);
+        let mut writer = spool.open().await.unwrap();
+
+        // Rejected points are never spooled
+        writer.write(&influx_db, point(0.1, 1)).await;
+        assert!(writer.is_empty());
+        writer.write(&influx_db, point(0.2, 2)).await;
+        assert_eq!(spool.status().pending_points, 1);
+        // Nor do they hold up the points spooled after them
+        writer.replay(&influx_db).await;
+        assert!(writer.is_empty());
+        writer.write(&influx_db, point(0.3, 3)).await;
+        for _ in 0..3 {
+            requests.recv().unwrap();
+        }
+        assert!(requests.recv().unwrap().ends_with("value=0.3 3\n"));
+        let status = spool.status();
+        assert_eq!((status.pending_points, status.dropped_points), (0, 2));
+        std::fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_spool_size_bound() {
+        let directory = std::env::temp_dir().join("energise_test_spool_size_bound");
+        std::fs::remove_dir_all(&directory).ok();
+        let spool = Spool::new(directory.clone(), 1000);
+        let mut writer = spool.open().await.unwrap();
+        for i in 0..100 {
+            writer.push(&point(0.1, i)).await.unwrap();
+        }
+        let status = spool.status();
+        assert!(status.size <= 1000);
+        assert!(status.dropped_points > 0);
+        assert_eq!(status.pending_points + status.dropped_points, 100);
+        std::fs::remove_dir_all(&directory).unwrap();
+    }
+}
@@ -0,0 +1,234 @@
+use crate::{Reading, Sink, SinkError, UsageData};
+use async_trait::async_trait;
+use chrono::Utc;
+use log::info;
+use rusqlite::{params, Connection};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::task;
+
+/// Schema migrations, applied in order. The number of applied migrations is
+/// kept in the database's `user_version`, so released migrations must never
+/// change, only be added to.
+const MIGRATIONS: [&str; 2] = [
+    "
+    CREATE TABLE telegrams (
+        id INTEGER PRIMARY KEY,
+        meter TEXT,
+        received INTEGER NOT NULL,
+        meter_time INTEGER,
+        raw TEXT
+    );
+    CREATE INDEX telegrams_meter_received ON telegrams (meter, received);
+    CREATE TABLE registers (
+        telegram_id INTEGER NOT NULL REFERENCES telegrams (id) ON DELETE CASCADE,
+        register TEXT NOT NULL,
+        value REAL NOT NULL,
+        unit TEXT NOT NULL,
+        PRIMARY KEY (telegram_id, register)
+    );
+    CREATE TABLE mbus_values (
+        telegram_id INTEGER NOT NULL REFERENCES telegrams (id) ON DELETE CASCADE,
+        channel INTEGER NOT NULL,
+        register TEXT NOT NULL,
+        captured INTEGER,
+        value REAL NOT NULL,
+        unit TEXT NOT NULL,
+        PRIMARY KEY (telegram_id, channel)
+    );
+",
+    "
+    CREATE INDEX telegrams_time ON telegrams (COALESCE(meter_time, received));
+",
+];
+
+/// Deletes telegrams older than `?1`, using the `telegrams_time` index.
+const PRUNE: &str = "DELETE FROM telegrams WHERE COALESCE(meter_time, received) < ?1";
+
+/// How often old telegrams are pruned.
+const PRUNE_INTERVAL: i64 = 3600;
+
+/// Local history in a SQLite database: every telegram with its raw text, a
+/// snapshot of its registers and its M-Bus values.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Sqlite {
+    pub path: PathBuf,
+    /// Telegrams older than this, by meter time, are deleted. All are kept
+    /// when not set.
+    pub retention: Option<Duration>,
+}
+
+impl Sqlite {
+    /// Opens the database, creating it and migrating its schema as needed.
+    pub fn open(&self) -> rusqlite::Result<SqliteSink> {
+        let mut connection = Connection::open(&self.path)?;
+        connection.pragma_update(None, "foreign_keys", true)?;
+        connection.pragma_update(None, "journal_mode", "WAL")?;
+        migrate(&mut connection)?;
+        Ok(SqliteSink {
+            connection: Arc::new(Mutex::new(connection)),
+            retention: self.retention,
+            pruned: 0,
+        })
+    }
+}
+
+fn migrate(connection: &mut Connection) -> rusqlite::Result<()> {
+    let version: usize = connection.query_row("PRAGMA user_version", [], |row| row.get(0))?;
+    for (i, migration) in MIGRATIONS.iter().enumerate().skip(version) {
+        info!("Migrating SQLite schema to version {}", i + 1);
+        let transaction = connection.transaction()?;
+        transaction.execute_batch(migration)?;
+        transaction.pragma_update(None, "user_version", i + 1)?;
+        transaction.commit()?;
+    }
+    Ok(())
+}
+
+/// Writes to the database, clones share the connection.
+#[derive(Clone)]
+pub struct SqliteSink {
+    connection: Arc<Mutex<Connection>>,
+    retention: Option<Duration>,
+    /// When old telegrams were last pruned, as a Unix timestamp.
+    pruned: i64,
+}
+
+fn insert(
+    connection: &mut Connection,
+    meter: Option<&str>,
+    data: &UsageData,
+) -> rusqlite::Result<()> {
+    let received = data.received.unwrap_or_else(Utc::now).timestamp();
+    let meter_time = match &data.electricity_timestamp {
+        Reading::Timestamp(t) => Some(t.timestamp.timestamp()),
+        Reading::Measurement(_) => None,
+    };
+    let raw = if data.lines.is_empty() {
+        None
+    } else {
+        Some(data.lines.join("\r\n"))
+    };
+    let transaction = connection.transaction()?;
+    transaction.execute(
+        "INSERT INTO telegrams (meter, received, meter_time, raw) VALUES (?1, ?2, ?3, ?4)",
+        params![meter, received, meter_time, raw],
+    )?;
+    let id = transaction.last_insert_rowid();
+    for (energy_type, name, reading) in data.readings().iter() {
+        let m = match reading {
+            Reading::Measurement(m) => m,
+            Reading::Timestamp(_) => continue,
+        };
+        if *energy_type == "gas" {
+            let captured = match &data.gas_timestamp {
+                Reading::Timestamp(t) => Some(t.timestamp.timestamp()),
+                Reading::Measurement(_) => None,
+            };
+            transaction.execute(
+                "INSERT INTO mbus_values (telegram_id, channel, register, captured, value, unit)
+                 VALUES (?1, 1, ?2, ?3, ?4, ?5)",
+                params![id, name, captured, m.value, m.unit],
+            )?;
+        } else {
+            transaction.execute(
+                "INSERT INTO registers (telegram_id, register, value, unit) VALUES (?1, ?2, ?3, ?4)",
+                params![id, name, m.value, m.unit],
+            )?;
+        }
+    }
+    transaction.commit()
+}
+
+#[async_trait]
+impl Sink for SqliteSink {
+    fn name(&self) -> String {
+        "SQLite".to_string()
+    }
+
+    async fn write(&mut self, meter: Option<&str>, data: &UsageData) -> Result<(), SinkError> {
+        let now = Utc::now().timestamp();
+        let prune_before = match self.retention {
+            Some(retention) if now - self.pruned >= PRUNE_INTERVAL => {
+                self.pruned = now;
+                Some(now - retention.as_secs() as i64)
+            }
+            _ => None,
+        };
+        let connection = self.connection.clone();
+        let meter = meter.map(str::to_string);
+        let data = data.clone();
+        task::spawn_blocking(move || {
+            let mut connection = connection.lock().unwrap();
+            insert(&mut connection, meter.as_deref(), &data)?;
+            if let Some(before) = prune_before {
+                let pruned = connection.execute(PRUNE, [before])?;
+                if pruned > 0 {
+                    info!("Pruned {} telegrams from SQLite", pruned);
+                }
+            }
+            Ok::<(), SinkError>(())
+        })
+        .await?
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{deserialise_p1_message, sample_telegram};
+
+    fn telegram(time: &str) -> UsageData {
+        let mut lines = sample_telegram();
+        lines[2] = format!("0-0:1.0.0({}W)", time);
+        let mut data = deserialise_p1_message(&lines).unwrap();
+        data.lines = lines;
+        data
+    }
+
+    #[tokio::test]
+    async fn test_sqlite_sink() {
+        let path = std::env::temp_dir().join("energise_test_sqlite_sink.db");
+        std::fs::remove_file(&path).ok();
+        let sqlite = Sqlite {
+            path: path.clone(),
+            retention: Some(Duration::from_secs(365 * 24 * 3600)),
+        };
+        let mut sink = sqlite.open().unwrap();
+        // Older than the retention, pruned right after it is written
+        sink.write(Some("house"), &telegram("201221010833"))
+            .await
+            .unwrap();
+        let now = Utc::now().format("%y%m%d%H%M%S").to_string();
+        sink.write(Some("house"), &telegram(&now)).await.unwrap();
+        drop(sink);
+
+        // Reopening leaves an up to date schema alone
+        let sink = sqlite.open().unwrap();
+        let connection = sink.connection.lock().unwrap();
+        let count = |sql: &str| -> i64 { connection.query_row(sql, [], |row| row.get(0)).unwrap() };
+        assert_eq!(count("PRAGMA user_version"), MIGRATIONS.len() as i64);
+        assert_eq!(count("SELECT COUNT(*) FROM telegrams"), 1);
+        let plan: String = connection
+            .query_row(&format!("EXPLAIN QUERY PLAN {}", PRUNE), [0], |row| {
+                row.get(3)
+            })
+            .unwrap();
+        assert!(plan.contains("USING INDEX telegrams_time"), "{}", plan);
+        assert_eq!(count("SELECT COUNT(*) FROM registers"), 8);
+        let (value, unit, captured): (f64, String, i64) = connection
+            .query_row(
+                "SELECT value, unit, captured FROM mbus_values WHERE channel = 1",
+                [],
+                |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+            )
+            .unwrap();
+        assert_eq!(
+            (value, unit.as_str(), captured),
+            (3799.479, "m3", 1608509111)
+        );
+        drop(connection);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,162 @@
+use crate::{Input, RawTelegram};
+use chrono::{DateTime, Utc};
+use log::{info, warn};
+use serde::Serialize;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// Telegram interval of DSMR 5 meters, older versions send every 10 seconds.
+const DSMR5_INTERVAL: u64 = 1;
+const DSMR_INTERVAL: u64 = 10;
+/// Missed telegrams before a meter counts as stalled.
+const MISSED_TELEGRAMS: u32 = 3;
+
+/// Whether a meter is still sending valid telegrams.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Liveness {
+    /// No valid telegram has been received yet.
+    Starting,
+    Alive,
+    /// No valid telegram arrived within the stall window, the input is
+    /// being restarted.
+    Stalled,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct WatchdogStatus {
+    pub liveness: Liveness,
+    pub last_telegram: Option<DateTime<Utc>>,
+    /// Version from the `1-3:0.2.8` line, e.g. `50` for DSMR 5.0.
+    pub dsmr_version: Option<String>,
+    pub restarts: u64,
+    /// Telegrams parsed successfully.
+    pub telegrams: u64,
+    pub parse_errors: u64,
+    /// Telegrams whose CRC did not match their contents.
+    pub crc_failures: u64,
+}
+
+/// Tracks meter liveness and counts telegrams. Clones share their state, so a clone can be kept to
+/// report on a meter that is being read elsewhere.
+#[derive(Debug, Clone)]
+pub struct Watchdog {
+    window: Option<Duration>,
+    status: Arc<Mutex<WatchdogStatus>>,
+}
+
+impl Watchdog {
+    /// Creates a watchdog that restarts an input after `window` without a
+    /// valid telegram, by default three times the interval of the detected
+    /// DSMR version.
+    pub fn new(window: Option<Duration>) -> Self {
+        Watchdog {
+            window,
+            status: Arc::new(Mutex::new(WatchdogStatus {
+                liveness: Liveness::Starting,
+                last_telegram: None,
+                dsmr_version: None,
+                restarts: 0,
+                telegrams: 0,
+                parse_errors: 0,
+                crc_failures: 0,
+            })),
+        }
+    }
+
+    pub fn status(&self) -> WatchdogStatus {
+        self.status.lock().unwrap().clone()
+    }
+
+    /// Stall window for `input`, replayed files are not watched since gaps in
+    /// a capture are expected.
+    pub(crate) fn window(&self, input: &Input) -> Option<Duration> {
+        let version_interval = match self.status().dsmr_version.as_deref() {
+            Some(version) if version.starts_with('5') => DSMR5_INTERVAL,
+            _ => DSMR_INTERVAL,
+        };
+        let interval = match input {
+            Input::Replay { .. } => return None,
+            Input::Http { interval, .. } => (*interval).max(Duration::from_secs(version_interval)),
+            _ => Duration::from_secs(version_interval),
+        };
+        Some(self.window.unwrap_or(interval * MISSED_TELEGRAMS))
+    }
+
+    pub(crate) fn alive(&self, telegram: &RawTelegram) {
+        let mut status = self.status.lock().unwrap();
+        if status.liveness == Liveness::Stalled {
+            info!("Receiving valid telegrams again");
+        }
+        status.liveness = Liveness::Alive;
+        status.telegrams += 1;
+        status.last_telegram = Some(telegram.received);
+        if let Some(version) = telegram
+            .lines
+            .iter()
+            .find_map(|l| l.strip_prefix("1-3:0.2.8("))
+        {
+            status.dsmr_version = Some(version.trim_end_matches(')').to_string());
+        }
+    }
+
+    pub(crate) fn stalled(&self, input: &Input, window: Duration) {
+        let mut status = self.status.lock().unwrap();
+        warn!(
+            "No valid telegram from {} for {:?}, restarting input",
+            input, window
+        );
+        status.liveness = Liveness::Stalled;
+    }
+
+    pub(crate) fn parse_failed(&self) {
+        self.status.lock().unwrap().parse_errors += 1;
+    }
+
+    pub(crate) fn crc_failed(&self) {
+        self.status.lock().unwrap().crc_failures += 1;
+    }
+
+    pub(crate) fn restarted(&self) {
+        self.status.lock().unwrap().restarts += 1;
+    }
+}
+
+impl Default for Watchdog {
+    fn default() -> Self {
+        Watchdog::new(None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_window_from_dsmr_version() {
+        let watchdog = Watchdog::default();
+        let input = Input::Tcp("localhost:8088".to_string());
+        assert_eq!(watchdog.window(&input), Some(Duration::from_secs(30)));
+
+        watchdog.alive(&RawTelegram {
+            received: Utc::now(),
+            lines: vec![
+                "/ISK5\\2M550E-1012".to_string(),
+                "1-3:0.2.8(50)".to_string(),
+            ],
+        });
+        assert_eq!(watchdog.status().liveness, Liveness::Alive);
+        assert_eq!(watchdog.status().dsmr_version, Some("50".to_string()));
+        assert_eq!(watchdog.window(&input), Some(Duration::from_secs(3)));
+
+        let replay = Input::Replay {
+            path: "capture.txt".into(),
+            speed: 1.0,
+        };
+        assert_eq!(watchdog.window(&replay), None);
+        assert_eq!(
+            Watchdog::new(Some(Duration::from_secs(60))).window(&input),
+            Some(Duration::from_secs(60))
+        );
+    }
+}
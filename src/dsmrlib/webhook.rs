@@ -0,0 +1,267 @@
+use crate::ndjson::Telegram;
+use crate::{Sink, SinkError, UsageData};
+use async_trait::async_trait;
+use handlebars::{no_escape, Handlebars};
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue, AUTHORIZATION, CONTENT_TYPE};
+use reqwest::{Client, Method, Url};
+use serde_json::Value;
+use std::error::Error;
+use std::fmt;
+use std::time::Duration;
+use tokio::time::Instant;
+
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Sends telegrams to an HTTP endpoint such as Node-RED.
+///
+/// Without a template the body is the telegram as JSON, in the format of the
+/// NDJSON sink, or a JSON array of telegrams when batching. A Handlebars
+/// template is rendered with the fields of the latest telegram and
+/// `telegrams`, every telegram of the batch.
+#[derive(Clone, PartialEq)]
+pub struct Webhook {
+    /// Endpoint, a user and password in the URL are sent as basic auth.
+    pub url: Url,
+    pub method: Method,
+    pub headers: Vec<(String, String)>,
+    /// Sent as a bearer token.
+    pub token: Option<String>,
+    pub template: Option<String>,
+    /// Number of telegrams sent in one request.
+    pub batch_size: usize,
+    /// Longest time telegrams are held back to fill a batch.
+    pub flush_interval: Duration,
+}
+
+impl Webhook {
+    pub fn new(url: Url) -> Self {
+        Webhook {
+            url,
+            method: Method::POST,
+            headers: Vec::new(),
+            token: None,
+            template: None,
+            batch_size: 1,
+            flush_interval: Duration::from_secs(10),
+        }
+    }
+
+    /// Checks the headers and compiles the template.
+    pub fn sink(&self) -> Result<WebhookSink, Box<dyn Error>> {
+        let mut headers = HeaderMap::new();
+        for (name, value) in &self.headers {
+            headers.insert(
+                HeaderName::from_bytes(name.as_bytes())?,
+                HeaderValue::from_str(value)?,
+            );
+        }
+        if let Some(token) = &self.token {
+            headers.insert(
+                AUTHORIZATION,
+                HeaderValue::from_str(&format!("Bearer {}", token))?,
+            );
+        }
+        if !headers.contains_key(CONTENT_TYPE) {
+            headers.insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
+        }
+        let mut url = self.url.clone();
+        let basic_auth = if url.username().is_empty() {
+            None
+        } else {
+            let auth = (
+                url.username().to_string(),
+                url.password().map(str::to_string),
+            );
+            url.set_username("").ok();
+            url.set_password(None).ok();
+            Some(auth)
+        };
+        let templates = match &self.template {
+            Some(template) => {
+                let mut templates = Handlebars::new();
+                templates.set_strict_mode(true);
+                templates.register_escape_fn(no_escape);
+                templates.register_template_string("payload", template)?;
+                Some(templates)
+            }
+            None => None,
+        };
+        Ok(WebhookSink {
+            client: Client::builder()
+                .timeout(TIMEOUT)
+                .default_headers(headers)
+                .build()?,
+            url,
+            method: self.method.clone(),
+            basic_auth,
+            templates,
+            batch_size: self.batch_size,
+            flush_interval: self.flush_interval,
+            pending: Vec::new(),
+            oldest: None,
+        })
+    }
+}
+
+impl fmt::Debug for Webhook {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut url = self.url.clone();
+        url.set_password(None).ok();
+        f.debug_struct("Webhook")
+            .field("url", &url.as_str())
+            .field("method", &self.method)
+            .field("batch_size", &self.batch_size)
+            .finish()
+    }
+}
+
+/// Clones share the HTTP client, not their batch.
+#[derive(Clone)]
+pub struct WebhookSink {
+    client: Client,
+    url: Url,
+    method: Method,
+    basic_auth: Option<(String, Option<String>)>,
+    templates: Option<Handlebars<'static>>,
+    batch_size: usize,
+    flush_interval: Duration,
+    /// Telegrams as JSON, waiting for the batch to fill.
+    pending: Vec<Value>,
+    /// When the oldest pending telegram was buffered.
+    oldest: Option<Instant>,
+}
+
+impl WebhookSink {
+    fn body(&self, telegrams: &[Value]) -> Result<String, SinkError> {
+        Ok(match &self.templates {
+            Some(templates) => {
+                let mut context = telegrams.last().cloned().unwrap_or_default();
+                context["telegrams"] = Value::Array(telegrams.to_vec());
+                templates.render("payload", &context)?
+            }
+            None if self.batch_size <= 1 && telegrams.len() == 1 => {
+                serde_json::to_string(&telegrams[0])?
+            }
+            None => serde_json::to_string(telegrams)?,
+        })
+    }
+
+    async fn send(&self, telegrams: &[Value]) -> Result<(), SinkError> {
+        let mut request = self
+            .client
+            .request(self.method.clone(), self.url.clone())
+            .body(self.body(telegrams)?);
+        if let Some((user, password)) = &self.basic_auth {
+            request = request.basic_auth(user, password.as_ref());
+        }
+        request.send().await?.error_for_status()?;
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl Sink for WebhookSink {
+    fn name(&self) -> String {
+        format!("webhook {}", self.url)
+    }
+
+    async fn write(&mut self, meter: Option<&str>, data: &UsageData) -> Result<(), SinkError> {
+        let telegram = serde_json::to_value(Telegram { meter, data })?;
+        let oldest = *self.oldest.get_or_insert_with(Instant::now);
+        if self.pending.len() + 1 < self.batch_size && oldest.elapsed() < self.flush_interval {
+            self.pending.push(telegram);
+            return Ok(());
+        }
+        // Only buffered once sent, so a retried write doesn't add its telegram twice
+        let mut batch = self.pending.clone();
+        batch.push(telegram);
+        self.send(&batch).await?;
+        self.pending.clear();
+        self.oldest = None;
+        Ok(())
+    }
+
+    async fn flush(&mut self) -> Result<(), SinkError> {
+        match self.oldest {
+            Some(oldest) if oldest.elapsed() >= self.flush_interval => {}
+            _ => return Ok(()),
+        }
+        if !self.pending.is_empty() {
+            self.send(&self.pending).await?;
+            self.pending.clear();
+        }
+        self.oldest = None;
+        Ok(())
+    }
+
+    async fn close(&mut self) -> Result<(), SinkError> {
+        if !self.pending.is_empty() {
+            let batch = std::mem::take(&mut self.pending);
+            self.send(&batch).await?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::{serve, Response};
+    use crate::sample_usage;
+
+    #[tokio::test]
+    async fn test_webhook() {
+        let (address, requests) = serve(vec![
+            Response::status(500),
+            Response::ok("text/plain", ""),
+            Response::ok("text/plain", ""),
+        ]);
+        let data = sample_usage();
+
+        let mut webhook =
+            Webhook::new(Url::parse(&format!("http://{}/readings", address)).unwrap());
+        webhook.method = Method::PUT;
+        webhook.headers = vec![("X-Site".to_string(), "home".to_string())];
+        webhook.token = Some("secret".to_string());
+        webhook.template = Some(
+            "{\"meter\": \"{{meter}}\", \"power\": [{{#each telegrams}}\
+             {{power_receiving.value}}{{#unless @last}}, {{/unless}}{{/each}}]}"
+                .to_string(),
+        );
+        webhook.batch_size = 2;
+        let mut sink = webhook.sink().unwrap();
+        sink.write(Some("house"), &data).await.unwrap();
+        assert!(sink.write(Some("house"), &data).await.is_err());
+        sink.write(Some("house"), &data).await.unwrap();
+        requests.recv().unwrap();
+        let request = requests.recv().unwrap().to_lowercase();
+        assert!(request.starts_with("put /readings http/1.1\r\n"));
+        assert!(request.contains("\r\nx-site: home\r\n"));
+        assert!(request.contains("\r\nauthorization: bearer secret\r\n"));
+        assert!(request.ends_with("\r\n\r\n{\"meter\": \"house\", \"power\": [0.229, 0.229]}"));
+
+        let webhook = Webhook::new(Url::parse(&format!("http://user:pass@{}/", address)).unwrap());
+        let mut sink = webhook.sink().unwrap();
+        sink.write(None, &data).await.unwrap();
+        let request = requests.recv().unwrap();
+        assert!(request.contains("authorization: Basic dXNlcjpwYXNz\r\n"));
+        assert!(request.contains("content-type: application/json\r\n"));
+        let body: Value = serde_json::from_str(request.split("\r\n\r\n").nth(1).unwrap()).unwrap();
+        assert_eq!(body["power_receiving"]["value"], 0.229);
+
+        // A batch that doesn't fill is sent once it has waited long enough
+        let (address, requests) = serve(vec![Response::ok("text/plain", "")]);
+        let mut webhook = Webhook::new(Url::parse(&format!("http://{}/", address)).unwrap());
+        webhook.batch_size = 10;
+        webhook.flush_interval = Duration::from_millis(100);
+        let mut sink = webhook.sink().unwrap();
+        sink.write(None, &data).await.unwrap();
+        sink.flush().await.unwrap();
+        assert!(requests.try_recv().is_err());
+        tokio::time::sleep(webhook.flush_interval).await;
+        sink.flush().await.unwrap();
+        let request = requests.recv().unwrap();
+        let body: Value = serde_json::from_str(request.split("\r\n\r\n").nth(1).unwrap()).unwrap();
+        assert_eq!(body.as_array().unwrap().len(), 1);
+    }
+}
@@ -1,2 +1,3 @@
 pub use self::influx_wrapper::InfluxDbClient;
-pub mod influx_wrapper;
\ No newline at end of file
+#[allow(clippy::module_inception)]
+pub mod influx_wrapper;
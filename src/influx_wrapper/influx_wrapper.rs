@@ -1,5 +1,6 @@
+use dsmrlib::{InfluxDb, InfluxDbV2};
 use influx_db_client::Client;
-use log::info;
+use log::{info, warn};
 use reqwest::Url;
 use std::env;
 use std::error::Error;
@@ -9,12 +10,26 @@ pub struct InfluxDbClient {
     pub address: String,
     pub port: String,
     pub database_name: String,
+    /// Major InfluxDB version, 2 selects the token authenticated
+    /// `/api/v2/write` API which InfluxDB 3.x also serves.
+    pub version: String,
+    pub org: String,
+    pub bucket: String,
+    pub token: String,
 }
 
 impl InfluxDbClient {
-    pub async fn setup_database(&self) -> Result<Client, Box<dyn Error>> {
+    pub async fn setup_database(&self) -> Result<InfluxDb, Box<dyn Error>> {
         let url = Url::parse(&format!("{}:{}", self.address, self.port))?;
         info!("influx: {}", url);
+        match self.version.as_str() {
+            "1" => self.setup_v1(url).await,
+            "2" | "3" => self.setup_v2(url).await,
+            version => Err(format!("Unsupported InfluxDB version: {}", version).into()),
+        }
+    }
+
+    async fn setup_v1(&self, url: Url) -> Result<InfluxDb, Box<dyn Error>> {
         let mut client = Client::new(url, &self.database_name);
         client.switch_database(&self.database_name);
         let db_exists = client.ping().await;
@@ -24,10 +39,34 @@ impl InfluxDbClient {
             )));
         }
         match client.create_database(&self.database_name).await {
-            Ok(_) => Ok(client),
+            Ok(_) => Ok(InfluxDb::V1(client)),
             Err(e) => Err(Box::new(e)),
         }
     }
+
+    // The bucket has to exist already, the token usually only grants write access
+    async fn setup_v2(&self, url: Url) -> Result<InfluxDb, Box<dyn Error>> {
+        let client = InfluxDbV2::new(url, &self.org, &self.bucket, &self.token);
+        // InfluxDB 3.x has no bucket API, its token is only checked by writing
+        let checked = match self.version.as_str() {
+            "3" => client.ping().await.map(|()| true),
+            _ => client.check_bucket().await,
+        };
+        match checked {
+            Ok(true) => {}
+            Ok(false) => warn!(
+                "Bucket {} is not visible to the token, it is checked by the first write",
+                self.bucket
+            ),
+            Err(e) => {
+                return Err(Box::new(influx_db_client::Error::Communication(format!(
+                    "Cannot use Influx DB: {}",
+                    e
+                ))))
+            }
+        }
+        Ok(InfluxDb::V2(client))
+    }
 }
 
 impl Default for InfluxDbClient {
@@ -36,6 +75,10 @@ impl Default for InfluxDbClient {
             address: get_env_var("INFLUX_DB_ADDRESS"),
             port: get_env_var("INFLUX_DB_PORT"),
             database_name: get_env_var("INFLUX_DB_NAME"),
+            version: env::var("INFLUX_DB_VERSION").unwrap_or_else(|_| "1".to_string()),
+            org: get_env_var("INFLUX_DB_ORG"),
+            bucket: env::var("INFLUX_DB_BUCKET").unwrap_or_else(|_| get_env_var("INFLUX_DB_NAME")),
+            token: get_env_var("INFLUX_DB_TOKEN"),
         }
     }
 }
@@ -1,26 +1,330 @@
-mod dsmrlib;
+mod config;
 mod influx_wrapper;
+use dsmrlib::archive::sort_archive_files;
+use dsmrlib::{
+    export_archive, DropPolicy, DsmrClient, History, HttpServer, InfluxSink, Input, Live, Metrics,
+    Ndjson, Output, Retry, Spool, TimestampSource, Watchdog,
+};
 use log::{error, info};
+use std::future::pending;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::time::Duration;
+use tokio::signal::ctrl_c;
+#[cfg(unix)]
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::watch;
 
 #[tokio::main]
 async fn main() {
     env_logger::init();
-    let influxdb_client = influx_wrapper::InfluxDbClient {
-        ..Default::default()
+    let args: Vec<String> = std::env::args().collect();
+    if args.get(1).map(String::as_str) == Some("export") {
+        return export(&args[2..]).await;
+    }
+    // InfluxDB can be left out when readings go elsewhere, e.g. only scraped by Prometheus
+    let influx_db = match config::get_env::<String>("INFLUX_DB_ADDRESS") {
+        Ok(Some(_)) => {
+            let influxdb_client = influx_wrapper::InfluxDbClient {
+                ..Default::default()
+            };
+            match influxdb_client.setup_database().await {
+                Ok(client) => Some(client),
+                Err(e) => return error!("{}", e),
+            }
+        }
+        Ok(None) => None,
+        Err(e) => return error!("{}", e),
+    };
+    let influx_schema = match config::influx_schema() {
+        Ok(schema) => schema,
+        Err(e) => return error!("{}", e),
+    };
+    let meters = match config::meters() {
+        Ok(meters) => meters,
+        Err(e) => return error!("{}", e),
+    };
+    let archive = match config::archive() {
+        Ok(archive) => archive,
+        Err(e) => return error!("{}", e),
+    };
+    let mqtt = match config::mqtt() {
+        Ok(mqtt) => mqtt.map(|mqtt| mqtt.connect()),
+        Err(e) => return error!("{}", e),
+    };
+    let mqtt = match mqtt.transpose() {
+        Ok(mqtt) => mqtt,
+        Err(e) => return error!("Unable to set up MQTT: {}", e),
+    };
+    let http_address: Option<SocketAddr> = match config::get_env("DSMR_HTTP_ADDRESS") {
+        Ok(address) => address,
+        Err(e) => return error!("{}", e),
+    };
+    let spool = match config::spool() {
+        Ok(spool) => spool,
+        Err(e) => return error!("{}", e),
+    };
+    let sqlite = match config::sqlite() {
+        Ok(sqlite) => sqlite.map(|sqlite| {
+            sqlite
+                .open()
+                .map_err(|e| format!("Unable to open {}: {}", sqlite.path.display(), e))
+        }),
+        Err(e) => return error!("{}", e),
+    };
+    let sqlite = match sqlite.transpose() {
+        Ok(sqlite) => sqlite,
+        Err(e) => return error!("{}", e),
+    };
+    let postgres = match config::postgres() {
+        Ok(postgres) => postgres,
+        Err(e) => return error!("{}", e),
+    };
+    if let Some(postgres) = &postgres {
+        if let Err(e) = postgres.setup_database().await {
+            return error!("Unable to set up PostgreSQL: {}", e);
+        }
+    }
+    let export = match config::export() {
+        Ok(export) => export,
+        Err(e) => return error!("{}", e),
+    };
+    let graphite = match config::graphite() {
+        Ok(graphite) => graphite,
+        Err(e) => return error!("{}", e),
+    };
+    let stdout = match config::get_env("DSMR_STDOUT_JSON") {
+        Ok(stdout) => stdout.unwrap_or(false),
+        Err(e) => return error!("{}", e),
+    };
+    let webhook = match config::webhook() {
+        Ok(webhook) => webhook.map(|webhook| webhook.sink()),
+        Err(e) => return error!("{}", e),
+    };
+    let webhook = match webhook.transpose() {
+        Ok(webhook) => webhook,
+        Err(e) => return error!("Unable to set up the webhook: {}", e),
+    };
+    let webhook_retry = match config::get_env("DSMR_WEBHOOK_RETRIES") {
+        Ok(attempts) => Retry {
+            attempts: attempts.unwrap_or(5),
+            ..Retry::default()
+        },
+        Err(e) => return error!("{}", e),
+    };
+    let queue_size = match config::get_env("DSMR_QUEUE_SIZE") {
+        Ok(size) => size.unwrap_or(64),
+        Err(e) => return error!("{}", e),
+    };
+    let drop_policy: Option<DropPolicy> = match config::get_env("DSMR_DROP_POLICY") {
+        Ok(policy) => policy,
+        Err(e) => return error!("{}", e),
     };
-    let influx_db = influxdb_client.setup_database().await;
-    let serial_device: String = "/dev/ttyUSB0".to_string();
+    let stall_timeout = match config::get_env("DSMR_STALL_TIMEOUT") {
+        Ok(timeout) => timeout.map(Duration::from_secs),
+        Err(e) => return error!("{}", e),
+    };
+    let aggregate = match config::get_env("DSMR_AGGREGATE_INTERVAL") {
+        Ok(interval) => interval.map(Duration::from_secs),
+        Err(e) => return error!("{}", e),
+    };
+    let timestamps = match config::get_env("DSMR_TIMESTAMPS") {
+        Ok(source) => source.unwrap_or(TimestampSource::Meter),
+        Err(e) => return error!("{}", e),
+    };
+
+    let metrics = http_address.map(|_| Metrics::default());
+    let live = http_address.map(|_| Live::default());
+    let history = match config::get_env("DSMR_HISTORY_HOURS") {
+        Ok(hours) => {
+            http_address.map(|_| History::new(chrono::Duration::hours(hours.unwrap_or(24))))
+        }
+        Err(e) => return error!("{}", e),
+    };
+    if let Some(address) = http_address {
+        let server = HttpServer {
+            address,
+            metrics: metrics.clone(),
+            live: live.clone(),
+            history: history.clone(),
+        };
+        if let Err(e) = server.spawn() {
+            return error!("Unable to serve HTTP on {}: {}", address, e);
+        }
+    }
+    if influx_db.is_none()
+        && mqtt.is_none()
+        && metrics.is_none()
+        && sqlite.is_none()
+        && postgres.is_none()
+        && export.is_none()
+        && !stdout
+        && webhook.is_none()
+        && graphite.is_none()
+    {
+        return error!("Nothing to write to, configure at least one output");
+    }
+    if let Some(client) = &influx_db {
+        info!("influx_db: {:?}", client);
+    }
+
+    // Stopping the meters lets the sinks write out what they have buffered
+    let (stop, stopped) = watch::channel(());
+    tokio::spawn(async move {
+        shutdown_signal().await;
+        info!("Shutting down");
+        stop.send(()).ok();
+    });
+
+    // Every meter runs in its own task so a failing meter leaves the others running
+    let readers: Vec<_> = meters
+        .into_iter()
+        .map(|meter| {
+            let archive = archive.clone().map(|mut archive| {
+                if let Some(label) = &meter.label {
+                    archive.directory.push(label);
+                }
+                archive
+            });
+            // Clones share their status, so every meter gets its own spool
+            let spool = spool.as_ref().map(|spool| {
+                let mut directory = spool.directory.clone();
+                if let Some(label) = &meter.label {
+                    directory.push(label);
+                }
+                Spool::new(directory, spool.max_size)
+            });
+            // Replays are backfills, so wait for the sinks instead of losing telegrams
+            let drop_policy = drop_policy.unwrap_or(match meter.input {
+                Input::Replay { .. } => DropPolicy::Block,
+                _ => DropPolicy::DropOldest,
+            });
+            let output = |output: Output| Output {
+                queue_size,
+                drop_policy,
+                ..output
+            };
+            if let (Some(metrics), Some(spool), Some(_)) = (&metrics, &spool, &influx_db) {
+                metrics.spool(meter.label.as_deref(), spool.clone());
+            }
+            let mut sinks = Vec::new();
+            if let Some(client) = &influx_db {
+                sinks.push(output(Output::new(InfluxSink::new(
+                    client.clone(),
+                    timestamps,
+                    influx_schema.clone(),
+                    spool,
+                ))));
+            }
+            if let Some(mqtt) = &mqtt {
+                sinks.push(output(Output::new(mqtt.clone())));
+            }
+            if let Some(sqlite) = &sqlite {
+                sinks.push(output(Output::new(sqlite.clone())));
+            }
+            if let Some(export) = &export {
+                let mut export = export.clone();
+                if let Some(label) = &meter.label {
+                    export.directory.push(label);
+                }
+                sinks.push(output(Output::new(export.sink())));
+            }
+            if stdout {
+                sinks.push(output(Output::new(Ndjson::stdout())));
+            }
+            if let Some(webhook) = &webhook {
+                sinks.push(output(Output {
+                    retry: webhook_retry,
+                    ..Output::new(webhook.clone())
+                }));
+            }
+            if let Some(postgres) = &postgres {
+                sinks.push(output(Output::new(postgres.sink(timestamps))));
+            }
+            if let Some(graphite) = &graphite {
+                sinks.push(output(Output::new(graphite.sink(timestamps))));
+            }
+            let watchdog = Watchdog::new(stall_timeout);
+            if let Some(metrics) = &metrics {
+                metrics.watch(meter.label.as_deref(), watchdog.clone());
+                sinks.push(output(Output::new(metrics.clone())));
+            }
+            if let Some(live) = &live {
+                sinks.push(output(Output::new(live.clone())));
+            }
+            if let Some(history) = &history {
+                sinks.push(output(Output::new(history.clone())));
+            }
+            let client = DsmrClient {
+                meter: meter.label,
+                input: meter.input,
+                archive,
+                queue_size,
+                drop_policy,
+                watchdog,
+                aggregate,
+                sinks,
+            };
+            let mut stopped = stopped.clone();
+            tokio::spawn(client.run_until(async move {
+                stopped.changed().await.ok();
+            }))
+        })
+        .collect();
+    for reader in readers {
+        if let Err(e) = reader.await {
+            error!("Meter reader failed: {}", e);
+        }
+    }
+}
 
-    match influx_db {
-        Ok(client) => {
-            info!("influx_db: {:?}", client);
-            dsmrlib::DsmrClient {
-                serial_device: serial_device,
-                influx_db: client,
-            }
-            .send_to_influxdb()
-            .await
+/// Waits for Ctrl-C or SIGTERM, as sent by `docker stop`.
+async fn shutdown_signal() {
+    let interrupt = async {
+        if let Err(e) = ctrl_c().await {
+            error!("Unable to handle Ctrl-C: {}", e);
+            pending::<()>().await;
         }
-        Err(e) => error!("{}", e),
+    };
+    #[cfg(unix)]
+    let terminate = async {
+        match signal(SignalKind::terminate()) {
+            Ok(mut terminate) => {
+                terminate.recv().await;
+            }
+            Err(e) => {
+                error!("Unable to handle SIGTERM: {}", e);
+                pending::<()>().await;
+            }
+        }
+    };
+    #[cfg(not(unix))]
+    let terminate = pending::<()>();
+    tokio::select! {
+        _ = interrupt => {}
+        _ = terminate => {}
+    }
+}
+
+/// `energise export <archive files>...` converts archived telegrams to the
+/// files configured with `DSMR_EXPORT_DIR`.
+async fn export(paths: &[String]) {
+    let export = match config::export() {
+        Ok(Some(export)) => export,
+        Ok(None) => return error!("Set DSMR_EXPORT_DIR to export to"),
+        Err(e) => return error!("{}", e),
+    };
+    if paths.is_empty() {
+        return error!("Usage: energise export <archive files>...");
+    }
+    let mut paths: Vec<PathBuf> = paths.iter().map(PathBuf::from).collect();
+    sort_archive_files(&mut paths);
+    match export_archive(&paths, None, &mut export.sink()).await {
+        Ok(count) => info!(
+            "Exported {} telegrams to {}",
+            count,
+            export.directory.display()
+        ),
+        Err(e) => error!("Export failed: {}", e),
     }
 }